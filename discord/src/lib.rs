@@ -1,7 +1,35 @@
-use std::fmt::Display;
+#[macro_use]
+extern crate log;
 
-use overwatch::{owatapi::OWTODAY_URL, GameMode};
-use serenity::{builder::CreateMessage, model::id::ChannelId, prelude::*, utils::Colour};
+use std::{fmt::Display, thread, time::Duration};
+
+use chrono::{offset::Utc, DateTime};
+use failure::Fail;
+use overwatch::{
+    i18n::{Text, Translator},
+    owatapi::OWTODAY_URL,
+    template::{ImageStyle, Template},
+    GameMode,
+};
+use serde_json::{json, Value};
+use serenity::{http::HttpError, model::id::ChannelId, prelude::*};
+
+pub use serenity::Client;
+
+/// Discord won't take more than this many embeds in one message
+const MAX_EMBEDS: usize = 10;
+const MAX_RETRIES: u32 = 4;
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Sending gamemodes failed part way, after the first `delivered` went out
+#[derive(Debug, Fail)]
+#[fail(display = "failed after sending {} gamemodes: {}", delivered, error)]
+pub struct PartialDelivery {
+    pub delivered: usize,
+    #[fail(cause)]
+    pub error: failure::Error,
+}
 
 struct Handler;
 impl EventHandler for Handler {}
@@ -10,6 +38,66 @@ pub fn create_client<S: AsRef<str>>(token: S) -> Result<Client, failure::Error>
     Ok(Client::new(&token, Handler)?)
 }
 
+fn http_error(err: &serenity::Error) -> Option<&HttpError> {
+    match err {
+        serenity::Error::Http(ref e) => {
+            let e: &HttpError = e;
+            Some(e)
+        }
+        _ => None,
+    }
+}
+
+fn serenity_status(err: &serenity::Error) -> Option<u16> {
+    match http_error(err)? {
+        HttpError::UnsuccessfulRequest(ref resp) => Some(resp.status_code.as_u16()),
+        _ => None,
+    }
+}
+
+/// The HTTP status discord responded with, if that is why we failed
+pub fn error_status(err: &failure::Error) -> Option<u16> {
+    let err = err
+        .iter_chain()
+        .find_map(|e| e.downcast_ref::<serenity::Error>())?;
+    serenity_status(err)
+}
+
+/// Whether retrying won't help, e.g. the channel is gone or we were kicked out
+pub fn is_permanent_error(err: &failure::Error) -> bool {
+    matches!(error_status(err), Some(401) | Some(403) | Some(404))
+}
+
+/// Call discord, backing off from server errors
+///
+/// serenity already waits out rate limits itself, so we never see a 429.
+fn with_retry<T, F>(mut f: F) -> Result<T, serenity::Error>
+where
+    F: FnMut() -> Result<T, serenity::Error>,
+{
+    let mut attempt = 0;
+    loop {
+        let err = match f() {
+            Ok(r) => return Ok(r),
+            Err(e) => e,
+        };
+        attempt += 1;
+        let wait = match serenity_status(&err) {
+            Some(status) if status >= 500 => BASE_BACKOFF * 2u32.pow(attempt - 1),
+            _ => return Err(err),
+        };
+        if attempt > MAX_RETRIES {
+            return Err(err);
+        }
+        let wait = wait.min(MAX_BACKOFF);
+        warn!(
+            "discord request failed ({}), retrying in {:?} (attempt {}/{})",
+            err, wait, attempt, MAX_RETRIES
+        );
+        thread::sleep(wait);
+    }
+}
+
 pub fn send_message<D: Display>(
     client: &Client,
     channel: u64,
@@ -17,25 +105,103 @@ pub fn send_message<D: Display>(
 ) -> Result<(), failure::Error> {
     let chttp = client.cache_and_http.http.clone();
     let ch = ChannelId(channel);
-    ch.say(&chttp, message)?;
+    let message = message.to_string();
+    with_retry(|| ch.say(&chttp, &message))?;
     Ok(())
 }
 
-pub fn send_gamemode(client: &Client, channel: u64, gm: &GameMode) -> Result<(), failure::Error> {
+fn embed(
+    gm: &GameMode,
+    template: &Template,
+    tr: &Translator,
+    date: DateTime<Utc>,
+) -> Result<Value, failure::Error> {
+    let gm = &tr.gamemode(gm);
+    let mut fields = vec![json!({
+        "name": tr.text(Text::Players),
+        "value": gm.players,
+        "inline": true,
+    })];
+    if let Some(ref label) = gm.label {
+        fields.push(json!({
+            "name": tr.text(Text::Label),
+            "value": label,
+            "inline": true,
+        }));
+    }
+    let mut e = json!({
+        "title": template.title(gm, date)?,
+        "url": OWTODAY_URL,
+        "color": template.colour(gm)?,
+        "timestamp": date.to_rfc3339(),
+        "footer": {"text": tr.text(Text::NewInArcade)},
+        "fields": fields,
+    });
+    if let Some(body) = template.body(gm, date)? {
+        e["description"] = json!(body);
+    }
+    if let Some(ref img) = gm.image {
+        match template.image {
+            ImageStyle::Full => e["image"] = json!({ "url": img.url }),
+            ImageStyle::Thumbnail => e["thumbnail"] = json!({ "url": img.url }),
+            ImageStyle::None => {}
+        }
+    }
+    Ok(e)
+}
+
+pub fn send_gamemode(
+    client: &Client,
+    channel: u64,
+    gm: &GameMode,
+    template: &Template,
+    tr: &Translator,
+    date: DateTime<Utc>,
+) -> Result<(), failure::Error> {
+    send_gamemodes(client, channel, &[(gm, date)], template, tr)
+}
+
+/// The embeds `send_gamemodes` would send, one per gamemode
+pub fn render_gamemodes(
+    gamemodes: &[(&GameMode, DateTime<Utc>)],
+    template: &Template,
+    tr: &Translator,
+) -> Result<Vec<Value>, failure::Error> {
+    gamemodes
+        .iter()
+        .map(|(gm, date)| embed(gm, template, tr, *date))
+        .collect()
+}
+
+/// Announce several gamemodes, with as few messages as discord allows
+///
+/// If a message fails after others were sent, the error is a
+/// `PartialDelivery` saying how many gamemodes, in order, already went out.
+pub fn send_gamemodes(
+    client: &Client,
+    channel: u64,
+    gamemodes: &[(&GameMode, DateTime<Utc>)],
+    template: &Template,
+    tr: &Translator,
+) -> Result<(), failure::Error> {
+    let embeds = render_gamemodes(gamemodes, template, tr)?;
+
     let chttp = client.cache_and_http.http.clone();
-    let ch = ChannelId(channel);
-    ch.send_message(&chttp, |m: &mut CreateMessage| {
-        m.embed(|e| {
-            e.title(&gm.name);
-            e.url(OWTODAY_URL);
-            e.color(Colour::from_rgb(0x07, 0x85, 0x3e));
-            e.field("Players", &gm.players, true);
-            if let Some(ref img) = gm.image {
-                e.image(&img.url);
+    let mut delivered = 0;
+    for chunk in embeds.chunks(MAX_EMBEDS) {
+        let msg = json!({ "embeds": chunk });
+        match with_retry(|| chttp.send_message(channel, &msg)) {
+            Ok(_) => delivered += chunk.len(),
+            Err(e) if delivered == 0 => return Err(e.into()),
+            Err(e) => {
+                return Err(PartialDelivery {
+                    delivered,
+                    error: e.into(),
+                }
+                .into())
             }
-            e
-        })
-    })?;
+        }
+    }
 
     Ok(())
 }
@@ -1,54 +1,732 @@
 use super::GameMode;
-use std::collections::HashSet;
-use stupids3::{get_obj, put, StupidS3Error};
+use crate::arcade_watcher::Target;
+use chrono::{offset::Utc, DateTime, Duration};
+use failure::Fail;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    fmt,
+};
+use stupids3::{Expect, ObjectStore, StupidS3Error, Versioned};
+
+/// The gamestate schema this code writes, and the newest it can read
+pub const STATE_SCHEMA: u32 = 1;
+/// How many old versions of each state object to keep by default
+pub const DEFAULT_HISTORY: usize = 48;
+/// Sorts the same as it reads, so history keys list oldest first
+const VERSION_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
 
 pub struct GameDiff {
     pub added: HashSet<GameMode>,
     pub removed: HashSet<GameMode>,
 }
 
+impl GameDiff {
+    pub fn between<'a, 'b>(
+        prev: impl Iterator<Item = &'a GameMode>,
+        next: impl Iterator<Item = &'b GameMode>,
+    ) -> GameDiff {
+        let prev = prev.cloned().collect::<HashSet<_>>();
+        let next = next.cloned().collect::<HashSet<_>>();
+        GameDiff {
+            added: next.difference(&prev).cloned().collect(),
+            removed: prev.difference(&next).cloned().collect(),
+        }
+    }
+}
+
+/// How a mode is named in `GameState::gone_since`, the same two modes are equal
+fn mode_key(gm: &GameMode) -> String {
+    format!("{} ({})", gm.name, gm.players)
+}
+
+/// A gamemode announcement which has not been delivered yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub target: Target,
+    pub gamemode: GameMode,
+    /// When the arcade rotation containing this gamemode was published
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub attempts: u32,
+    /// Held back until then, because of the target's quiet hours
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<DateTime<Utc>>,
+}
+
+impl Notification {
+    /// Whether it can be delivered at `now`
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        match self.not_before {
+            Some(not_before) => not_before <= now,
+            None => true,
+        }
+    }
+
+    /// Whether both notifications announce the same thing to the same place
+    pub fn is_same(&self, other: &Notification) -> bool {
+        self.target == other.target && self.gamemode == other.gamemode
+    }
+}
+
+/// How deliveries to a target have been going
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TargetHealth {
+    /// Failures in a row which retrying won't fix, like a deleted channel
+    pub permanent_failures: u32,
+    pub last_error: Option<String>,
+    /// Disabled targets are skipped until they are re-enabled by hand
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl TargetHealth {
+    /// Record a failed delivery, returns true if this disabled the target
+    pub fn record_failure(&mut self, error: String, permanent: bool, disable_after: u32) -> bool {
+        self.last_error = Some(error);
+        if !permanent {
+            return false;
+        }
+        self.permanent_failures += 1;
+        if !self.disabled && self.permanent_failures >= disable_after {
+            self.disabled = true;
+            return true;
+        }
+        false
+    }
+}
+
+/// Everything waiting on delivery
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Outbox {
+    pub pending: Vec<Notification>,
+    /// Only targets which have been failing are tracked
+    #[serde(default)]
+    pub targets: BTreeMap<Target, TargetHealth>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OutboxFormat {
+    Outbox(Outbox),
+    /// Before we tracked targets, the outbox was only the pending list
+    Pending(Vec<Notification>),
+}
+
+/// The gamestate object: what was in the arcade the last time we looked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameState {
+    pub schema: u32,
+    /// Missing if this was migrated from the legacy format
+    pub written_at: Option<DateTime<Utc>>,
+    /// The version of the code that wrote it
+    pub writer: Option<String>,
+    /// When the arcade rotation it describes was published
+    pub created_at: Option<DateTime<Utc>>,
+    pub modes: Vec<GameMode>,
+    /// The first rotation each mode which left the arcade was missing from,
+    /// like `Total Mayhem (6v6)`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub gone_since: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl GameState {
+    pub fn new(modes: Vec<GameMode>, created_at: DateTime<Utc>) -> GameState {
+        GameState {
+            schema: STATE_SCHEMA,
+            written_at: Some(Utc::now()),
+            writer: Some(env!("CARGO_PKG_VERSION").to_string()),
+            created_at: Some(created_at),
+            modes,
+            gone_since: BTreeMap::new(),
+        }
+    }
+
+    /// The state once the arcade moves on to `modes`, remembering what left
+    pub fn next(
+        prev: Option<&GameState>,
+        modes: Vec<GameMode>,
+        created_at: DateTime<Utc>,
+    ) -> GameState {
+        let mut gone_since = prev.map(|p| p.gone_since.clone()).unwrap_or_default();
+        for gm in &modes {
+            gone_since.remove(&mode_key(gm));
+        }
+        for gm in prev.iter().flat_map(|p| &p.modes) {
+            if !modes.contains(gm) {
+                gone_since.entry(mode_key(gm)).or_insert(created_at);
+            }
+        }
+        let mut state = GameState::new(modes, created_at);
+        state.gone_since = gone_since;
+        state
+    }
+
+    /// How long `gm` had been gone from the arcade at `at`, or `None` if we
+    /// never saw it leave
+    pub fn absence(&self, gm: &GameMode, at: DateTime<Utc>) -> Option<Duration> {
+        self.gone_since.get(&mode_key(gm)).map(|since| at - *since)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GameStateFormat {
+    GameState(GameState),
+    /// Before schema 1, the gamestate was only the list of modes
+    Legacy(Vec<GameMode>),
+}
+
+impl GameStateFormat {
+    fn migrate(self) -> GameState {
+        match self {
+            GameStateFormat::GameState(state) => state,
+            GameStateFormat::Legacy(modes) => GameState {
+                schema: STATE_SCHEMA,
+                written_at: None,
+                writer: None,
+                created_at: None,
+                modes,
+                gone_since: BTreeMap::new(),
+            },
+        }
+    }
+}
+
 pub trait ArcadeState {
-    fn previous_modes(&self) -> Result<Vec<GameMode>, failure::Error>;
+    /// The stored gamestate, or `None` if there isn't one yet
+    fn game_state(&self) -> Result<Option<GameState>, failure::Error>;
+    fn set_game_state(&mut self, state: &GameState) -> Result<(), failure::Error>;
+    fn previous_modes(&self) -> Result<Vec<GameMode>, failure::Error> {
+        Ok(self.game_state()?.map(|s| s.modes).unwrap_or_default())
+    }
+    /// Overwrite the gamestate without looking at what was there
     fn set_modes<'a>(
         &mut self,
         modes: impl Iterator<Item = &'a GameMode>,
-    ) -> Result<(), failure::Error>;
+        created_at: DateTime<Utc>,
+    ) -> Result<(), failure::Error> {
+        self.set_game_state(&GameState::new(modes.cloned().collect(), created_at))
+    }
+    /// Notifications left over from previous runs, and the health of each target
+    fn outbox(&self) -> Result<Outbox, failure::Error>;
+    fn set_outbox(&mut self, outbox: &Outbox) -> Result<(), failure::Error>;
     fn mode_diff<'a>(
         &'a self,
         modes: impl Iterator<Item = &'a GameMode>,
     ) -> Result<GameDiff, failure::Error> {
-        let next = modes.cloned().collect::<HashSet<_>>();
-        let prev = self.previous_modes()?.into_iter().collect::<HashSet<_>>();
-        Ok(GameDiff {
-            added: next.difference(&prev).cloned().collect(),
-            removed: prev.difference(&next).cloned().collect(),
-        })
+        Ok(GameDiff::between(self.previous_modes()?.iter(), modes))
     }
 }
 
+/// State we found but couldn't use
+///
+/// Carrying on with empty state would announce everything in the arcade
+/// again, so we stop until someone restores or resets it.
+#[derive(Debug, Fail)]
+pub enum StateError {
+    #[fail(display = "state {} is corrupted", key)]
+    Corrupted {
+        key: String,
+        #[fail(cause)]
+        error: StupidS3Error,
+    },
+    #[fail(
+        display = "state {} is corrupted, a copy was kept at {}",
+        key, quarantine
+    )]
+    Quarantined {
+        key: String,
+        quarantine: String,
+        #[fail(cause)]
+        error: StupidS3Error,
+    },
+    #[fail(
+        display = "state {} has schema {}, but we only understand up to {}",
+        key, schema, supported
+    )]
+    UnsupportedSchema {
+        key: String,
+        schema: u32,
+        supported: u32,
+    },
+}
+
+/// A copy of one state object, as it was written at some point
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateVersion {
+    /// When it was written, which is also how to refer to it
+    pub version: String,
+    /// The state object this is a copy of
+    pub key: String,
+}
+
+impl fmt::Display for StateVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}  {}", self.version, self.key)
+    }
+}
+
+/// What we know about an object's version from the last time we read it
+enum Seen {
+    Unread,
+    Missing,
+    ETag { etag: String, body: Vec<u8> },
+}
+
+/// State kept in S3, or any other object store
+///
+/// Writes only succeed if nobody else has written since we read, so when two
+/// runs overlap only one of them gets to claim the update. Every write also
+/// keeps a timestamped copy when something changed, so a bad write can be
+/// rolled back.
 pub struct S3State {
-    pub bucket: String,
-    pub keyname: String,
+    store: Box<dyn ObjectStore>,
+    keyname: String,
+    outbox_keyname: String,
+    modes_seen: RefCell<Seen>,
+    outbox_seen: RefCell<Seen>,
+    history: usize,
+    read_only: bool,
 }
 
-impl ArcadeState for S3State {
-    fn previous_modes(&self) -> Result<Vec<GameMode>, failure::Error> {
-        match get_obj::<Vec<GameMode>, _, _>(&self.bucket, &self.keyname) {
-            Ok(r) => Ok(r),
-            Err(s3_err) => match s3_err {
-                StupidS3Error::UnknownError { .. } => Err(s3_err.into()),
-                _ => {
-                    warn!("got error: {:#?}", s3_err);
-                    Ok(Vec::new())
+impl S3State {
+    pub fn new(store: Box<dyn ObjectStore>, keyname: String, outbox_keyname: String) -> S3State {
+        S3State {
+            store,
+            keyname,
+            outbox_keyname,
+            modes_seen: RefCell::new(Seen::Unread),
+            outbox_seen: RefCell::new(Seen::Unread),
+            history: DEFAULT_HISTORY,
+            read_only: false,
+        }
+    }
+
+    /// Never write to the store, not even a copy of corrupted state
+    pub fn read_only(mut self) -> S3State {
+        self.read_only = true;
+        self
+    }
+
+    fn check_writable(&self) -> Result<(), failure::Error> {
+        if self.read_only {
+            failure::bail!("the state is read only");
+        }
+        Ok(())
+    }
+
+    /// Keep this many old versions of each state object, 0 keeps none
+    pub fn keep_history(mut self, history: usize) -> S3State {
+        self.history = history;
+        self
+    }
+
+    /// Every version we still have of the state, oldest first
+    pub fn history(&self) -> Result<Vec<StateVersion>, failure::Error> {
+        let mut versions = Vec::new();
+        for key in &[&self.keyname, &self.outbox_keyname] {
+            let prefix = history_prefix(key);
+            for obj in self.store.list(&prefix)? {
+                versions.push(StateVersion {
+                    version: obj.key[prefix.len()..].to_string(),
+                    key: key.to_string(),
+                });
+            }
+        }
+        versions.sort_by(|a, b| a.version.cmp(&b.version));
+        Ok(versions)
+    }
+
+    /// Put the state back the way it was at `version`
+    ///
+    /// Each state object is restored to its last version written at or before
+    /// then. The restore is itself recorded in the history, so it can be undone.
+    pub fn restore(&self, version: &str) -> Result<Vec<StateVersion>, failure::Error> {
+        self.check_writable()?;
+        let history = self.history()?;
+        let mut restored = Vec::new();
+        for key in &[&self.keyname, &self.outbox_keyname] {
+            let found = history
+                .iter()
+                .rev()
+                .find(|v| &&v.key == key && v.version.as_str() <= version);
+            if let Some(v) = found {
+                restored.push(v.clone());
+            }
+        }
+        if restored.is_empty() {
+            failure::bail!("no state was written at or before {}", version);
+        }
+        for v in &restored {
+            let body = self
+                .store
+                .get_bytes(&format!("{}{}", history_prefix(&v.key), v.version))?
+                .value;
+            self.store
+                .put_bytes(&v.key, body.clone(), Expect::Anything)?;
+            self.record_history(&v.key, body);
+        }
+        Ok(restored)
+    }
+
+    /// Read an object, or `None` if it has never been written
+    fn read<S: DeserializeOwned>(
+        &self,
+        key: &str,
+        seen: &RefCell<Seen>,
+    ) -> Result<Option<S>, failure::Error> {
+        let raw = match self.store.get_bytes(key) {
+            Ok(raw) => raw,
+            Err(StupidS3Error::NoSuchKey { .. }) => {
+                *seen.borrow_mut() = Seen::Missing;
+                return Ok(None);
+            }
+            Err(error) if error.is_corrupt() => {
+                return Err(StateError::Corrupted {
+                    key: key.to_string(),
+                    error,
                 }
-            },
+                .into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        *seen.borrow_mut() = Seen::ETag {
+            etag: raw.etag.clone(),
+            body: raw.value.clone(),
+        };
+        match serde_json::from_slice(&raw.value) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if self.read_only => Err(StateError::Corrupted {
+                key: key.to_string(),
+                error: StupidS3Error::ContentInvalid(e.into()),
+            }
+            .into()),
+            Err(e) => Err(self
+                .quarantine(key, raw, StupidS3Error::ContentInvalid(e.into()))
+                .into()),
         }
     }
-    fn set_modes<'a>(
-        &mut self,
-        modes: impl Iterator<Item = &'a GameMode>,
+
+    /// Keep a copy of an object we couldn't parse, so it can be looked at later
+    ///
+    /// The copy is named after the ETag, so failing on the same object run
+    /// after run only keeps it once.
+    fn quarantine(&self, key: &str, raw: Versioned<Vec<u8>>, error: StupidS3Error) -> StateError {
+        let quarantine = format!("{}.corrupt.{}", key, raw.etag.trim_matches('"'));
+        match self
+            .store
+            .put_bytes(&quarantine, raw.value, Expect::Missing)
+        {
+            Ok(_) | Err(StupidS3Error::PreconditionFailed { .. }) => {
+                error!("state {} is corrupted, kept a copy at {}", key, quarantine);
+                StateError::Quarantined {
+                    key: key.to_string(),
+                    quarantine,
+                    error,
+                }
+            }
+            Err(e) => {
+                error!("could not keep a copy of corrupted state {}: {}", key, e);
+                StateError::Corrupted {
+                    key: key.to_string(),
+                    error,
+                }
+            }
+        }
+    }
+
+    fn write<S: Serialize>(
+        &self,
+        key: &str,
+        obj: &S,
+        seen: &RefCell<Seen>,
     ) -> Result<(), failure::Error> {
-        put(&self.bucket, &self.keyname, &modes.collect::<Vec<_>>())?;
+        self.check_writable()?;
+        let body = serde_json::to_string_pretty(obj)
+            .map_err(|e| StupidS3Error::Serialize(e.into()))?
+            .into_bytes();
+        // still written, since the write is what stops an overlapping run
+        let (etag, changed) = {
+            let current = seen.borrow();
+            let (expect, changed) = match *current {
+                Seen::Unread => (Expect::Anything, true),
+                Seen::Missing => (Expect::Missing, true),
+                Seen::ETag {
+                    ref etag,
+                    body: ref last,
+                } => (Expect::ETag(etag), !same_content(last, &body)),
+            };
+            (self.store.put_bytes(key, body.clone(), expect)?, changed)
+        };
+        *seen.borrow_mut() = Seen::ETag {
+            etag,
+            body: body.clone(),
+        };
+        if changed {
+            self.record_history(key, body);
+        }
         Ok(())
     }
+
+    /// Keep a timestamped copy of what we just wrote, and forget the oldest
+    ///
+    /// The write itself already succeeded, so a failure here is only logged.
+    fn record_history(&self, key: &str, body: Vec<u8>) {
+        if self.history == 0 {
+            return;
+        }
+        let prefix = history_prefix(key);
+        let version = Utc::now().format(VERSION_FORMAT);
+        let result = self
+            .store
+            .put_bytes(&format!("{}{}", prefix, version), body, Expect::Anything)
+            .and_then(|_| self.store.list(&prefix))
+            .and_then(|versions| {
+                let excess = versions.len().saturating_sub(self.history);
+                for old in &versions[..excess] {
+                    self.store.delete(&old.key)?;
+                }
+                Ok(())
+            });
+        if let Err(e) = result {
+            warn!("could not keep a backup of {}: {}", key, e);
+        }
+    }
+}
+
+/// Whether two versions of a state object say the same thing, ignoring when
+/// they were written
+fn same_content(a: &[u8], b: &[u8]) -> bool {
+    let parse = |raw| {
+        let mut value = serde_json::from_slice::<serde_json::Value>(raw).ok()?;
+        if let Some(obj) = value.as_object_mut() {
+            obj.remove("written_at");
+        }
+        Some(value)
+    };
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn history_prefix(key: &str) -> String {
+    format!("{}.history/", key)
+}
+
+impl ArcadeState for S3State {
+    fn game_state(&self) -> Result<Option<GameState>, failure::Error> {
+        let state = match self.read::<GameStateFormat>(&self.keyname, &self.modes_seen)? {
+            Some(format) => format.migrate(),
+            None => return Ok(None),
+        };
+        // Writing this back would throw away whatever the newer version added
+        if state.schema > STATE_SCHEMA {
+            return Err(StateError::UnsupportedSchema {
+                key: self.keyname.clone(),
+                schema: state.schema,
+                supported: STATE_SCHEMA,
+            }
+            .into());
+        }
+        Ok(Some(state))
+    }
+    fn set_game_state(&mut self, state: &GameState) -> Result<(), failure::Error> {
+        self.write(&self.keyname, state, &self.modes_seen)
+    }
+    fn outbox(&self) -> Result<Outbox, failure::Error> {
+        match self.read::<OutboxFormat>(&self.outbox_keyname, &self.outbox_seen)? {
+            Some(OutboxFormat::Outbox(outbox)) => Ok(outbox),
+            Some(OutboxFormat::Pending(pending)) => Ok(Outbox {
+                pending,
+                ..Default::default()
+            }),
+            None => Ok(Outbox::default()),
+        }
+    }
+    fn set_outbox(&mut self, outbox: &Outbox) -> Result<(), failure::Error> {
+        self.write(&self.outbox_keyname, outbox, &self.outbox_seen)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ArcadeState, GameState, Outbox, S3State, StateError, TargetHealth, STATE_SCHEMA};
+    use crate::{arcade_watcher::Target, GameMode};
+    use chrono::{offset::Utc, TimeZone};
+    use std::{thread, time::Duration};
+    use stupids3::{Expect, MemoryStore, ObjectStore};
+
+    #[test]
+    fn corrupted_state_is_quarantined() {
+        let store = MemoryStore::default();
+        store
+            .put_bytes("state.json", b"[{\"name\": ".to_vec(), Expect::Anything)
+            .unwrap();
+        // a dry run leaves it alone
+        let dry = S3State::new(
+            Box::new(store),
+            "state.json".to_string(),
+            "state.json.outbox".to_string(),
+        )
+        .read_only();
+        match dry
+            .previous_modes()
+            .unwrap_err()
+            .downcast_ref::<StateError>()
+        {
+            Some(StateError::Corrupted { .. }) => {}
+            r => panic!("expected Corrupted, got {:?}", r),
+        }
+        assert!(dry.store.list("state.json.corrupt").unwrap().is_empty());
+
+        let state = S3State::new(
+            dry.store,
+            "state.json".to_string(),
+            "state.json.outbox".to_string(),
+        );
+        let err = state.previous_modes().unwrap_err();
+        let quarantine = match err.downcast_ref::<StateError>() {
+            Some(StateError::Quarantined { quarantine, .. }) => quarantine.clone(),
+            _ => panic!("expected the state to be quarantined, got {}", err),
+        };
+        assert_eq!(state.store.get(&quarantine).unwrap(), "[{\"name\": ");
+        // the corrupted state stays where it was until someone fixes it
+        assert!(state.previous_modes().is_err());
+        assert!(state.outbox().unwrap().pending.is_empty());
+
+        // which is what `state reset` does
+        let mut state = state;
+        state.set_modes(std::iter::empty(), Utc::now()).unwrap();
+        assert!(state.previous_modes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restore_from_history() {
+        let mut state = S3State::new(
+            Box::new(MemoryStore::default()),
+            "state.json".to_string(),
+            "state.json.outbox".to_string(),
+        )
+        .keep_history(2);
+        let failures = |state: &S3State| {
+            state.outbox().unwrap().targets[&Target::Discord(1)].permanent_failures
+        };
+        for permanent_failures in 1..=3 {
+            let mut outbox = Outbox::default();
+            outbox.targets.insert(
+                Target::Discord(1),
+                TargetHealth {
+                    permanent_failures,
+                    ..Default::default()
+                },
+            );
+            state.set_outbox(&outbox).unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let history = state.history().unwrap();
+        assert_eq!(history.len(), 2);
+        let restored = state.restore(&history[0].version).unwrap();
+        assert_eq!(restored, vec![history[0].clone()]);
+        assert_eq!(failures(&state), 2);
+        assert_eq!(state.history().unwrap().len(), 2);
+        assert!(state.restore("2000").is_err());
+    }
+
+    #[test]
+    fn unchanged_writes_are_not_backed_up() {
+        let mut state = S3State::new(
+            Box::new(MemoryStore::default()),
+            "state.json".to_string(),
+            "state.json.outbox".to_string(),
+        );
+        let created_at = Utc::now();
+        for _ in 0..3 {
+            state.set_outbox(&Outbox::default()).unwrap();
+            // only the time it was written differs
+            state
+                .set_game_state(&GameState::new(vec![], created_at))
+                .unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(state.history().unwrap().len(), 2);
+
+        let mut outbox = Outbox::default();
+        outbox
+            .targets
+            .insert(Target::Discord(1), TargetHealth::default());
+        state.set_outbox(&outbox).unwrap();
+        assert_eq!(state.history().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn absence_counts_from_the_first_rotation_without_the_mode() {
+        let gm = |name: &str, players: &str| GameMode {
+            name: name.into(),
+            players: players.into(),
+            image: None,
+            description: None,
+            label: None,
+        };
+        let day = |d| Utc.with_ymd_and_hms(2019, 8, d, 0, 0, 0).unwrap();
+        let first = GameState::next(None, vec![gm("g1", "6v6"), gm("g2", "3v3")], day(1));
+        // g1 flickers out for a single day
+        let second = GameState::next(Some(&first), vec![gm("g1", "3v3")], day(2));
+        assert_eq!(
+            second.absence(&gm("g1", "6v6"), day(3)),
+            Some(chrono::Duration::days(1))
+        );
+        assert_eq!(second.absence(&gm("g1", "3v3"), day(3)), None);
+
+        let third = GameState::next(Some(&second), vec![gm("g1", "6v6")], day(3));
+        assert_eq!(third.absence(&gm("g1", "6v6"), day(4)), None);
+        assert_eq!(
+            third.absence(&gm("g2", "3v3"), day(4)),
+            Some(chrono::Duration::days(2))
+        );
+    }
+
+    #[test]
+    fn legacy_gamestate_is_migrated() {
+        let mut state = S3State::new(
+            Box::new(MemoryStore::default()),
+            "state.json".to_string(),
+            "state.json.outbox".to_string(),
+        );
+        state
+            .store
+            .put_bytes(
+                "state.json",
+                br#"[{"name": "g1", "players": "6v6"}]"#.to_vec(),
+                Expect::Anything,
+            )
+            .unwrap();
+        let legacy = state.game_state().unwrap().unwrap();
+        assert_eq!(legacy.schema, STATE_SCHEMA);
+        assert_eq!(legacy.writer, None);
+        assert_eq!(legacy.modes[0].name, "g1");
+
+        let now = Utc::now();
+        state.set_modes(legacy.modes.iter(), now).unwrap();
+        let current = state.game_state().unwrap().unwrap();
+        assert_eq!(current.created_at, Some(now));
+        assert!(current.writer.is_some());
+        assert_eq!(state.previous_modes().unwrap(), legacy.modes);
+
+        state
+            .store
+            .put_bytes(
+                "state.json",
+                br#"{"schema": 99, "modes": []}"#.to_vec(),
+                Expect::Anything,
+            )
+            .unwrap();
+        match state.game_state().unwrap_err().downcast_ref::<StateError>() {
+            Some(StateError::UnsupportedSchema { schema: 99, .. }) => {}
+            r => panic!("expected UnsupportedSchema, got {:?}", r),
+        }
+    }
 }
@@ -1,26 +1,261 @@
-use crate::{arcade_state::ArcadeState, owatapi::fetch_today, GameMode};
-use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use crate::{
+    arcade_state::{ArcadeState, GameDiff, GameState, Notification, Outbox, TargetHealth},
+    i18n::{validate_locale, Translator},
+    quiet::{parse_timezone, QuietHours},
+    template::Template,
+    Arcade, GameMode,
+};
+use chrono::{offset::Utc, Duration};
+use chrono_tz::Tz;
+use failure::Fail;
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+
+const DEFAULT_DELIVERY_ATTEMPTS: u32 = 5;
+const DEFAULT_DISABLE_AFTER: u32 = 3;
+
+/// Somewhere a notification can be delivered
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Target {
+    /// A discord channel ID
+    Discord(u64),
+    /// A named push subscription (ntfy, gotify, ...)
+    Push(String),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Discord(channel) => write!(f, "discord:{}", channel),
+            Target::Push(name) => write!(f, "push:{}", name),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Result<Target, failure::Error> {
+        match s.split_at(s.find(':').unwrap_or(0)) {
+            ("discord", channel) => Ok(Target::Discord(channel[1..].parse()?)),
+            ("push", name) => Ok(Target::Push(name[1..].to_string())),
+            _ => failure::bail!("invalid target {:?}", s),
+        }
+    }
+}
+
+impl Serialize for Target {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Target {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Target, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// The result of checking the arcade
+///
+/// Nothing is written to the state store until the update is passed back to
+/// `Watcher::claim`, so if we fail before then, the next run will try again.
+pub struct ArcadeUpdate {
+    /// What to record once the update is claimed, every mode currently in
+    /// the arcade
+    pub game_state: GameState,
+    /// Notifications from this update, plus any left over from earlier runs
+    pub notifications: Vec<Notification>,
+    pub targets: BTreeMap<Target, TargetHealth>,
+}
 
 pub struct Watcher<T> {
-    inner: HashMap<u64, HashSet<GameMode>>,
+    inner: HashMap<Target, HashSet<GameMode>>,
+    quiet: HashMap<Target, (QuietHours, Tz)>,
+    min_absence: HashMap<Target, Duration>,
+    delivery_attempts: u32,
     state: T,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct WatcherConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
     rooms: HashMap<u64, RoomConfig>,
+    /// Gamemode names by locale, then by their English name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    translations: HashMap<String, HashMap<String, String>>,
+    /// How many times to try a notification before giving up on it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delivery_attempts: Option<u32>,
+    /// How many permanent failures in a row before a target is disabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disable_after: Option<u32>,
+    /// Fields we don't understand, kept so they survive being pulled and pushed
+    #[serde(flatten)]
+    extra: BTreeMap<String, serde_json::Value>,
 }
 
 impl WatcherConfig {
     pub fn walk_rooms(&self) -> impl Iterator<Item = (u64, &Vec<GameMode>)> + '_ {
         self.rooms.iter().map(|(r, rc)| (*r, &rc.gamemodes))
     }
+
+    pub fn rooms(&self) -> impl Iterator<Item = (u64, &RoomConfig)> + '_ {
+        self.rooms.iter().map(|(r, rc)| (*r, rc))
+    }
+
+    pub fn room(&self, room: u64) -> Option<&RoomConfig> {
+        self.rooms.get(&room)
+    }
+
+    pub fn disable_after(&self) -> u32 {
+        self.disable_after.unwrap_or(DEFAULT_DISABLE_AFTER)
+    }
+
+    pub fn translator(&self, locale: Option<&str>) -> Translator<'_> {
+        Translator::new(locale, &self.translations)
+    }
+
+    /// Where each field we don't understand is, like `rooms.1234.colour`
+    pub fn unknown_fields(&self) -> Vec<String> {
+        let mut fields = self.extra.keys().cloned().collect::<Vec<_>>();
+        for (room, rc) in &self.rooms {
+            fields.extend(rc.extra.keys().map(|k| format!("rooms.{}.{}", room, k)));
+        }
+        fields.sort();
+        fields
+    }
+
+    /// Replace each room's group references with the gamemodes in those groups
+    pub fn expand_groups(
+        &mut self,
+        groups: &HashMap<String, Vec<GameMode>>,
+    ) -> Result<(), failure::Error> {
+        for (room, rc) in &mut self.rooms {
+            let names = std::mem::take(&mut rc.groups);
+            expand_groups(&mut rc.gamemodes, &names, groups)
+                .map_err(|e| e.context(format!("room {}", room)))?;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        for (room, rc) in &self.rooms {
+            if let Some(ref template) = rc.template {
+                template
+                    .validate()
+                    .map_err(|e| e.context(format!("room {}", room)))?;
+            }
+            if let Some(ref locale) = rc.locale {
+                validate_locale(locale).map_err(|e| e.context(format!("room {}", room)))?;
+            }
+            if let Some(ref timezone) = rc.timezone {
+                parse_timezone(timezone).map_err(|e| e.context(format!("room {}", room)))?;
+            }
+        }
+        for locale in self.translations.keys() {
+            validate_locale(locale).map_err(|e| e.context("translations"))?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct RoomConfig {
+    /// A human readable name for the channel, since the ID doesn't say much
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    /// Subscription groups whose gamemodes this room wants as well as its own
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    groups: Vec<String>,
+    #[serde(default)]
     gamemodes: Vec<GameMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template: Option<Template>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    locale: Option<String>,
+    /// An IANA time zone like `Europe/Berlin` for `quiet_hours`, defaults to UTC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+    /// Like `22:00-08:00`, announcements due then wait until it ends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    quiet_hours: Option<QuietHours>,
+    /// Only announce a mode once it has been gone from the arcade this many days
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_absence: Option<u32>,
+    #[serde(flatten)]
+    extra: BTreeMap<String, serde_json::Value>,
+}
+
+impl RoomConfig {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+    pub fn gamemodes(&self) -> &[GameMode] {
+        &self.gamemodes
+    }
+    pub fn template(&self) -> Option<&Template> {
+        self.template.as_ref()
+    }
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+    pub fn quiet_hours(&self) -> Option<QuietHours> {
+        self.quiet_hours
+    }
+    pub fn min_absence(&self) -> Option<Duration> {
+        self.min_absence.map(|days| Duration::days(days.into()))
+    }
+    /// UTC if the time zone is missing or invalid
+    pub fn timezone(&self) -> Tz {
+        match self.timezone.as_deref().map(parse_timezone) {
+            Some(Ok(tz)) => tz,
+            Some(Err(e)) => {
+                warn!("{}, using UTC", e);
+                Tz::UTC
+            }
+            None => Tz::UTC,
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "There is no subscription group called {:?}", _0)]
+pub struct UnknownGroup(pub String);
+
+/// Add the gamemodes of the named groups to `gamemodes`, skipping any it has
+pub fn expand_groups(
+    gamemodes: &mut Vec<GameMode>,
+    names: &[String],
+    groups: &HashMap<String, Vec<GameMode>>,
+) -> Result<(), UnknownGroup> {
+    for name in names {
+        let group = groups.get(name).ok_or_else(|| UnknownGroup(name.clone()))?;
+        for gm in group {
+            if !gamemodes.contains(gm) {
+                gamemodes.push(gm.clone());
+            }
+        }
+    }
+    Ok(())
 }
 
 impl<T: ArcadeState> Watcher<T> {
@@ -28,44 +263,261 @@ impl<T: ArcadeState> Watcher<T> {
         Watcher {
             inner: watcher_cfg
                 .walk_rooms()
-                .map(|(room, interested)| (room, interested.iter().cloned().collect()))
+                .map(|(room, interested)| {
+                    (Target::Discord(room), interested.iter().cloned().collect())
+                })
+                .collect(),
+            quiet: watcher_cfg
+                .rooms()
+                .filter_map(|(room, rc)| {
+                    Some((Target::Discord(room), (rc.quiet_hours()?, rc.timezone())))
+                })
                 .collect(),
+            min_absence: watcher_cfg
+                .rooms()
+                .filter_map(|(room, rc)| Some((Target::Discord(room), rc.min_absence()?)))
+                .collect(),
+            delivery_attempts: watcher_cfg
+                .delivery_attempts
+                .unwrap_or(DEFAULT_DELIVERY_ATTEMPTS),
             state,
         }
     }
 
-    pub fn update(&mut self) -> Result<HashMap<u64, HashSet<GameMode>>, failure::Error> {
-        let response = fetch_today()?;
-        if !response.success {
-            failure::bail!("failed to fetch: {:?}", response.message)
-        }
-        let arcade = response.data;
-        let current = arcade.modes.iter();
-        let diff = self.state.mode_diff(current.clone())?;
-        self.state.set_modes(current)?;
-
-        let mut result = HashMap::new();
-        for (room, wadd, _wrem) in self.inner.iter().filter_map(|(room, interested)| {
-            let watched_added = diff
-                .added
-                .intersection(interested)
-                .cloned()
-                .collect::<HashSet<GameMode>>();
-            let watched_removed = diff
-                .removed
-                .intersection(interested)
-                .cloned()
-                .collect::<HashSet<GameMode>>();
-
-            if !watched_added.is_empty() {
-                Some((room, watched_added, watched_removed))
-            } else {
-                None
+    /// Watch for additional gamemodes on behalf of a target
+    pub fn watch<'a>(&mut self, target: Target, interested: impl Iterator<Item = &'a GameMode>) {
+        self.inner
+            .entry(target)
+            .or_default()
+            .extend(interested.cloned());
+    }
+
+    /// Compare `arcade` to what we saw last time, and work out what to announce
+    pub fn update(&mut self, arcade: Arcade) -> Result<ArcadeUpdate, failure::Error> {
+        let prev = self.state.game_state()?;
+        let diff = GameDiff::between(prev.iter().flat_map(|p| &p.modes), arcade.modes.iter());
+
+        let Outbox {
+            pending: mut notifications,
+            targets,
+        } = self.state.outbox()?;
+        for (target, interested) in &self.inner {
+            for gm in diff.added.intersection(interested) {
+                let absence = prev.as_ref().and_then(|p| p.absence(gm, arcade.created_at));
+                match (self.min_absence.get(target), absence) {
+                    (Some(min), Some(absence)) if absence < *min => {
+                        debug!(
+                            "not announcing {} to {}, it was only gone for {} hours",
+                            gm,
+                            target,
+                            absence.num_hours()
+                        );
+                        continue;
+                    }
+                    _ => {}
+                }
+                let n = Notification {
+                    target: target.clone(),
+                    gamemode: gm.clone(),
+                    created_at: arcade.created_at,
+                    attempts: 0,
+                    not_before: None,
+                };
+                if !notifications.iter().any(|p| p.is_same(&n)) {
+                    notifications.push(n);
+                }
+            }
+        }
+        let now = Utc::now();
+        for n in &mut notifications {
+            if let Some((quiet, tz)) = self.quiet.get(&n.target) {
+                if let Some(until) = quiet.ends_after(now, *tz) {
+                    n.not_before = Some(until);
+                }
             }
-        }) {
-            result.insert(*room, wadd);
         }
 
-        Ok(result)
+        Ok(ArcadeUpdate {
+            game_state: GameState::next(prev.as_ref(), arcade.modes, arcade.created_at),
+            notifications,
+            targets,
+        })
+    }
+
+    /// Record the arcade as seen, with every notification from it pending
+    ///
+    /// This must succeed before anything is delivered. If another run got
+    /// here first, the state store refuses the write and we should back off,
+    /// since they will be announcing the same things.
+    pub fn claim(&mut self, update: &ArcadeUpdate) -> Result<(), failure::Error> {
+        self.state.set_outbox(&Outbox {
+            pending: update.notifications.clone(),
+            targets: update.targets.clone(),
+        })?;
+        self.state.set_game_state(&update.game_state)?;
+        Ok(())
+    }
+
+    /// Keep anything undelivered for next time
+    ///
+    /// Notifications which have already been tried too many times are dropped.
+    pub fn commit(&mut self, outbox: Outbox) -> Result<(), failure::Error> {
+        let max_attempts = self.delivery_attempts;
+        let pending = outbox
+            .pending
+            .into_iter()
+            .filter(|n| {
+                if n.attempts >= max_attempts {
+                    error!(
+                        "giving up on {} for {} after {} attempts",
+                        n.gamemode, n.target, n.attempts
+                    );
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect::<Vec<_>>();
+        self.state.set_outbox(&Outbox {
+            pending,
+            targets: outbox.targets,
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ArcadeUpdate, Target, Watcher, WatcherConfig};
+    use crate::{
+        arcade_state::{Outbox, S3State},
+        Arcade, GameMode,
+    };
+    use chrono::{offset::Utc, TimeZone};
+    use std::sync::Arc;
+    use stupids3::{MemoryStore, StupidS3Error};
+
+    fn arcade(day: u32, names: &[&str]) -> Arcade {
+        Arcade {
+            is_today: true,
+            created_at: Utc.with_ymd_and_hms(2019, 8, day, 0, 0, 0).unwrap(),
+            modes: names
+                .iter()
+                .map(|name| GameMode {
+                    name: name.to_string(),
+                    players: "6v6".into(),
+                    image: None,
+                    description: None,
+                    label: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// A watcher for room 1, which wants ctf and gives up after two attempts
+    fn watcher(store: &Arc<MemoryStore>) -> Watcher<S3State> {
+        let cfg: WatcherConfig = serde_json::from_value(serde_json::json!({
+            "delivery_attempts": 2,
+            "rooms": {"1": {"gamemodes": [{"name": "ctf", "players": "6v6"}]}}
+        }))
+        .unwrap();
+        let state = S3State::new(
+            Box::new(store.clone()),
+            "state.json".to_string(),
+            "state.json.outbox".to_string(),
+        )
+        .keep_history(0);
+        Watcher::new(state, &cfg)
+    }
+
+    /// Claim the update, and fail to deliver any of it
+    fn fail_delivery(watcher: &mut Watcher<S3State>, update: &ArcadeUpdate) {
+        watcher.claim(update).unwrap();
+        let mut pending = update.notifications.clone();
+        for n in &mut pending {
+            n.attempts += 1;
+        }
+        watcher
+            .commit(Outbox {
+                pending,
+                targets: update.targets.clone(),
+            })
+            .unwrap();
+    }
+
+    fn attempts(update: &ArcadeUpdate) -> Vec<u32> {
+        update.notifications.iter().map(|n| n.attempts).collect()
+    }
+
+    #[test]
+    fn undelivered_notifications_are_retried_then_dropped() {
+        let store = Arc::new(MemoryStore::default());
+        let mut w = watcher(&store);
+        let update = w.update(arcade(1, &["ctf"])).unwrap();
+        assert_eq!(attempts(&update), vec![0]);
+        fail_delivery(&mut w, &update);
+
+        // nothing new in the arcade, but the failed notification is still due
+        let mut w = watcher(&store);
+        let update = w.update(arcade(2, &["ctf"])).unwrap();
+        assert_eq!(attempts(&update), vec![1]);
+        fail_delivery(&mut w, &update);
+
+        let update = watcher(&store).update(arcade(3, &["ctf"])).unwrap();
+        assert!(update.notifications.is_empty());
+    }
+
+    #[test]
+    fn leftover_notifications_are_not_duplicated() {
+        let store = Arc::new(MemoryStore::default());
+        // each run dies after claiming, before anything is delivered
+        for (day, names) in [(1, &["ctf"][..]), (2, &[]), (3, &["ctf"])] {
+            let mut w = watcher(&store);
+            let update = w.update(arcade(day, names)).unwrap();
+            assert_eq!(attempts(&update), vec![0]);
+            w.claim(&update).unwrap();
+        }
+    }
+
+    #[test]
+    fn overlapping_runs_only_claim_once() {
+        let store = Arc::new(MemoryStore::default());
+        let (mut first, mut second) = (watcher(&store), watcher(&store));
+        let first_update = first.update(arcade(1, &["ctf"])).unwrap();
+        let second_update = second.update(arcade(1, &["ctf"])).unwrap();
+        first.claim(&first_update).unwrap();
+        let err = second.claim(&second_update).unwrap_err();
+        match err.downcast_ref::<StupidS3Error>() {
+            Some(StupidS3Error::PreconditionFailed { .. }) => {}
+            _ => panic!("expected PreconditionFailed, got {}", err),
+        }
+    }
+
+    #[test]
+    fn target_roundtrip() {
+        for t in &[Target::Discord(1234), Target::Push("phone:work".into())] {
+            assert_eq!(&t.to_string().parse::<Target>().unwrap(), t);
+        }
+        assert!("discord:abc".parse::<Target>().is_err());
+        assert!("1234".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn unknown_fields_survive_roundtrip() {
+        let json = r#"{
+            "comment": "prod",
+            "owner": "me",
+            "rooms": {
+                "1": {"name": "general", "comment": "c", "gamemodes": [], "colour": "red"}
+            }
+        }"#;
+        let cfg: WatcherConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(cfg.room(1).unwrap().name(), Some("general"));
+        assert_eq!(cfg.room(1).unwrap().comment(), Some("c"));
+        assert_eq!(cfg.unknown_fields(), vec!["owner", "rooms.1.colour"]);
+        assert_eq!(
+            serde_json::to_value(&cfg).unwrap(),
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        );
     }
 }
@@ -0,0 +1,185 @@
+use crate::GameMode;
+use failure::Fail;
+use std::collections::HashMap;
+
+const DEFAULT_LOCALE: &str = "en";
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum I18nError {
+    #[fail(display = "no translations are available for locale {:?}", _0)]
+    UnknownLocale(String),
+}
+
+/// A piece of announcement text that can be translated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Text {
+    Players,
+    Label,
+    NewInArcade,
+}
+
+struct Catalog {
+    locale: &'static str,
+    players: &'static str,
+    label: &'static str,
+    new_in_arcade: &'static str,
+}
+
+const CATALOGS: &[Catalog] = &[
+    Catalog {
+        locale: "en",
+        players: "Players",
+        label: "Label",
+        new_in_arcade: "New in the arcade",
+    },
+    Catalog {
+        locale: "de",
+        players: "Spieler",
+        label: "Kategorie",
+        new_in_arcade: "Neu in der Arcade",
+    },
+    Catalog {
+        locale: "es",
+        players: "Jugadores",
+        label: "Etiqueta",
+        new_in_arcade: "Nuevo en el arcade",
+    },
+    Catalog {
+        locale: "fr",
+        players: "Joueurs",
+        label: "Catégorie",
+        new_in_arcade: "Nouveau dans l'arcade",
+    },
+    Catalog {
+        locale: "pt",
+        players: "Jogadores",
+        label: "Categoria",
+        new_in_arcade: "Novo no fliperama",
+    },
+];
+
+/// A locale as `language-region` in lower case, and just its language
+fn normalize(locale: &str) -> (String, String) {
+    let locale = locale.to_lowercase().replace('_', "-");
+    let language = locale.split('-').next().unwrap_or_default().to_string();
+    (locale, language)
+}
+
+fn catalog(locale: &str) -> Option<&'static Catalog> {
+    let (locale, language) = normalize(locale);
+    CATALOGS
+        .iter()
+        .find(|c| c.locale == locale)
+        .or_else(|| CATALOGS.iter().find(|c| c.locale == language))
+}
+
+/// The gamemode names for a locale, falling back from a region to its
+/// language like `catalog`
+fn mode_names<'a>(
+    mode_names: &'a HashMap<String, HashMap<String, String>>,
+    locale: &str,
+) -> Option<&'a HashMap<String, String>> {
+    let (locale, language) = normalize(locale);
+    let find = |wanted: &str| {
+        mode_names
+            .iter()
+            .find(|(k, _)| normalize(k).0 == wanted)
+            .map(|(_, names)| names)
+    };
+    find(&locale).or_else(|| find(&language))
+}
+
+/// Check that a locale has a translation catalog
+pub fn validate_locale(locale: &str) -> Result<(), I18nError> {
+    catalog(locale)
+        .map(|_| ())
+        .ok_or_else(|| I18nError::UnknownLocale(locale.to_string()))
+}
+
+/// Translates announcement text, and optionally gamemode names, for a locale
+///
+/// Unknown locales fall back to English, so a bad locale will never block an
+/// announcement.
+#[derive(Clone, Copy)]
+pub struct Translator<'a> {
+    catalog: &'static Catalog,
+    mode_names: Option<&'a HashMap<String, String>>,
+}
+
+impl<'a> Translator<'a> {
+    pub fn new(
+        locale: Option<&str>,
+        mode_names: &'a HashMap<String, HashMap<String, String>>,
+    ) -> Translator<'a> {
+        let locale = locale.unwrap_or(DEFAULT_LOCALE);
+        let catalog = catalog(locale).unwrap_or_else(|| {
+            warn!("no translations for locale {:?}, using English", locale);
+            &CATALOGS[0]
+        });
+        Translator {
+            catalog,
+            mode_names: self::mode_names(mode_names, locale),
+        }
+    }
+
+    pub fn text(&self, text: Text) -> &'static str {
+        match text {
+            Text::Players => self.catalog.players,
+            Text::Label => self.catalog.label,
+            Text::NewInArcade => self.catalog.new_in_arcade,
+        }
+    }
+
+    /// A copy of the gamemode with its name translated, if we know how
+    pub fn gamemode(&self, gm: &GameMode) -> GameMode {
+        let mut gm = gm.clone();
+        if let Some(name) = self.mode_names.and_then(|m| m.get(&gm.name)) {
+            gm.name = name.clone();
+        }
+        gm
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn region_falls_back_to_language() {
+        let names = HashMap::new();
+        let tr = Translator::new(Some("de_AT"), &names);
+        assert_eq!(tr.text(Text::Players), "Spieler");
+        assert_eq!(validate_locale("fr-CA"), Ok(()));
+        assert!(validate_locale("tlh").is_err());
+    }
+
+    #[test]
+    fn translate_mode_name() {
+        let mut names = HashMap::new();
+        names.insert(
+            "de".to_string(),
+            vec![("Total Mayhem".to_string(), "Totales Chaos".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        let gm = GameMode {
+            name: "Total Mayhem".into(),
+            players: "6v6".into(),
+            image: None,
+            description: None,
+            label: None,
+        };
+        assert_eq!(
+            Translator::new(Some("de"), &names).gamemode(&gm).name,
+            "Totales Chaos"
+        );
+        assert_eq!(
+            Translator::new(Some("de_AT"), &names).gamemode(&gm).name,
+            "Totales Chaos"
+        );
+        assert_eq!(
+            Translator::new(Some("fr"), &names).gamemode(&gm).name,
+            "Total Mayhem"
+        );
+    }
+}
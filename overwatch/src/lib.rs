@@ -2,12 +2,16 @@
 extern crate log;
 
 use chrono::{self, offset::Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::hash::Hash;
 
 pub mod arcade_state;
 pub mod arcade_watcher;
+pub mod i18n;
 pub mod owatapi;
+pub mod quiet;
+pub mod template;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TodayResponse {
@@ -19,12 +23,12 @@ pub struct TodayResponse {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Arcade {
-    is_today: bool,
-    created_at: chrono::DateTime<Utc>,
-    modes: Vec<GameMode>,
+    pub is_today: bool,
+    pub created_at: chrono::DateTime<Utc>,
+    pub modes: Vec<GameMode>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct GameMode {
     pub name: String,
     pub players: String,
@@ -71,7 +75,7 @@ impl fmt::Display for GameMode {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(transparent)]
 pub struct GameImage {
     #[serde(flatten)]
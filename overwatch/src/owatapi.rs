@@ -1,4 +1,4 @@
-use super::TodayResponse;
+use super::{Arcade, TodayResponse};
 use reqwest;
 
 const OWAPI_BASE: &str = "https://overwatcharcade.today/api/v1/overwatch";
@@ -7,3 +7,12 @@ pub const OWTODAY_URL: &str = "https://overwatcharcade.today/overwatch";
 pub fn fetch_today() -> Result<TodayResponse, failure::Error> {
     Ok(reqwest::get(&format!("{}/today", OWAPI_BASE))?.json()?)
 }
+
+/// Today's arcade, failing if the API couldn't give us one
+pub fn fetch_arcade() -> Result<Arcade, failure::Error> {
+    let response = fetch_today()?;
+    if !response.success {
+        failure::bail!("failed to fetch: {:?}", response.message)
+    }
+    Ok(response.data)
+}
@@ -0,0 +1,121 @@
+use chrono::{offset::Utc, DateTime, Duration, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+const TIME_FORMAT: &str = "%H:%M";
+
+/// A daily window when announcements should wait, like `22:00-08:00`
+///
+/// The window may wrap around midnight. A window which starts and ends at
+/// the same time is never quiet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    fn contains(&self, t: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+
+    /// When the quiet hours around `now` end, or `None` if it isn't quiet in `tz`
+    pub fn ends_after(&self, now: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let local = now.with_timezone(&tz).naive_local();
+        if !self.contains(local.time()) {
+            return None;
+        }
+        let mut date = local.date();
+        if self.end <= local.time() {
+            date = date.succ_opt()?;
+        }
+        let end = date.and_time(self.end);
+        // the end might fall in a gap when the clocks go forward
+        let end = tz.from_local_datetime(&end).earliest().or_else(|| {
+            tz.from_local_datetime(&(end + Duration::hours(1)))
+                .earliest()
+        })?;
+        Some(end.with_timezone(&Utc))
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format(TIME_FORMAT),
+            self.end.format(TIME_FORMAT)
+        )
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Result<QuietHours, failure::Error> {
+        let (start, end) = match s.find('-') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => failure::bail!("invalid quiet hours {:?}, expected HH:MM-HH:MM", s),
+        };
+        Ok(QuietHours {
+            start: NaiveTime::parse_from_str(start.trim(), TIME_FORMAT)?,
+            end: NaiveTime::parse_from_str(end.trim(), TIME_FORMAT)?,
+        })
+    }
+}
+
+impl Serialize for QuietHours {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for QuietHours {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<QuietHours, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Parse an IANA time zone name like `Europe/Berlin`
+pub fn parse_timezone(name: &str) -> Result<Tz, failure::Error> {
+    name.parse()
+        .map_err(|e| failure::format_err!("unknown time zone {:?}: {}", name, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_timezone, QuietHours};
+    use chrono::{offset::Utc, TimeZone};
+
+    #[test]
+    fn quiet_hours_wrap_midnight() {
+        let quiet: QuietHours = "22:00-08:00".parse().unwrap();
+        assert_eq!(quiet.to_string(), "22:00-08:00");
+        let berlin = parse_timezone("Europe/Berlin").unwrap();
+
+        // 00:00 UTC is 02:00 in Berlin in the summer, so wait until 06:00 UTC
+        let midnight = Utc.with_ymd_and_hms(2019, 8, 30, 0, 0, 0).unwrap();
+        assert_eq!(
+            quiet.ends_after(midnight, berlin),
+            Some(Utc.with_ymd_and_hms(2019, 8, 30, 6, 0, 0).unwrap())
+        );
+        // 21:00 UTC is 23:00 in Berlin, so wait for the next morning
+        let evening = Utc.with_ymd_and_hms(2019, 8, 30, 21, 0, 0).unwrap();
+        assert_eq!(
+            quiet.ends_after(evening, berlin),
+            Some(Utc.with_ymd_and_hms(2019, 8, 31, 6, 0, 0).unwrap())
+        );
+        let noon = Utc.with_ymd_and_hms(2019, 8, 30, 12, 0, 0).unwrap();
+        assert_eq!(quiet.ends_after(noon, berlin), None);
+
+        assert!("22:00".parse::<QuietHours>().is_err());
+        assert!(parse_timezone("Mars/Olympus_Mons").is_err());
+    }
+}
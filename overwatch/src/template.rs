@@ -0,0 +1,229 @@
+use crate::GameMode;
+use chrono::{offset::Utc, DateTime};
+use failure::Fail;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_TITLE: &str = "{name}";
+const DEFAULT_BODY: &str = "{description}";
+const DEFAULT_COLOUR: u32 = 0x07_85_3e;
+const WEEKLY_COLOUR: u32 = 0x21_8f_fe;
+const PERMANENT_COLOUR: u32 = 0xf9_9e_1a;
+const PLACEHOLDERS: &[&str] = &["name", "players", "description", "label", "date"];
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum TemplateError {
+    #[fail(display = "unknown placeholder {{{}}} in {:?}", _0, _1)]
+    UnknownPlaceholder(String, String),
+    #[fail(display = "unterminated placeholder in {:?}", _0)]
+    Unterminated(String),
+    #[fail(display = "invalid colour {:?}, expected a hex value like #07853e", _0)]
+    InvalidColour(String),
+}
+
+/// How the gamemode's image should be attached to an announcement
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageStyle {
+    #[default]
+    Full,
+    Thumbnail,
+    None,
+}
+
+/// The layout of an announcement
+///
+/// `title` and `body` are format strings which may refer to `{name}`,
+/// `{players}`, `{description}`, `{label}` and `{date}`. Use `{{` and `}}`
+/// for literal braces. Without a `colour`, the mode's label picks one.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Template {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub colour: Option<String>,
+    #[serde(default)]
+    pub image: ImageStyle,
+}
+
+impl Template {
+    pub fn validate(&self) -> Result<(), TemplateError> {
+        for fmt in self.title.iter().chain(self.body.iter()) {
+            render(fmt, |_| Some(""))?;
+        }
+        if let Some(ref c) = self.colour {
+            parse_colour(c)?;
+        }
+        Ok(())
+    }
+
+    pub fn title(&self, gm: &GameMode, date: DateTime<Utc>) -> Result<String, TemplateError> {
+        render(self.title.as_deref().unwrap_or(DEFAULT_TITLE), |key| {
+            placeholder(gm, date, key)
+        })
+    }
+
+    /// The rendered body, or `None` if it came out empty
+    pub fn body(
+        &self,
+        gm: &GameMode,
+        date: DateTime<Utc>,
+    ) -> Result<Option<String>, TemplateError> {
+        let body = render(self.body.as_deref().unwrap_or(DEFAULT_BODY), |key| {
+            placeholder(gm, date, key)
+        })?;
+        Ok(if body.trim().is_empty() {
+            None
+        } else {
+            Some(body)
+        })
+    }
+
+    /// The colour as a `0xRRGGBB` value
+    pub fn colour(&self, gm: &GameMode) -> Result<u32, TemplateError> {
+        match self.colour {
+            Some(ref c) => parse_colour(c),
+            None => Ok(label_colour(gm.label.as_deref())),
+        }
+    }
+}
+
+fn parse_colour(c: &str) -> Result<u32, TemplateError> {
+    u32::from_str_radix(c.trim_start_matches('#'), 16)
+        .ok()
+        .filter(|v| *v <= 0xff_ff_ff)
+        .ok_or_else(|| TemplateError::InvalidColour(c.to_string()))
+}
+
+fn label_colour(label: Option<&str>) -> u32 {
+    match label.map(|l| l.to_lowercase()).as_deref() {
+        Some("weekly") => WEEKLY_COLOUR,
+        Some("permanent") => PERMANENT_COLOUR,
+        _ => DEFAULT_COLOUR,
+    }
+}
+
+fn placeholder(gm: &GameMode, date: DateTime<Utc>, key: &str) -> Option<String> {
+    Some(match key {
+        "name" => gm.name.clone(),
+        "players" => gm.players.clone(),
+        "description" => gm.description.clone().unwrap_or_default(),
+        "label" => gm.label.clone().unwrap_or_default(),
+        "date" => date.format("%Y-%m-%d").to_string(),
+        _ => return None,
+    })
+}
+
+fn render<F, S>(fmt: &str, lookup: F) -> Result<String, TemplateError>
+where
+    F: Fn(&str) -> Option<S>,
+    S: AsRef<str>,
+{
+    let mut out = String::with_capacity(fmt.len());
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let mut key = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(k) => key.push(k),
+                        None => return Err(TemplateError::Unterminated(fmt.to_string())),
+                    }
+                }
+                if !PLACEHOLDERS.contains(&key.as_str()) {
+                    return Err(TemplateError::UnknownPlaceholder(key, fmt.to_string()));
+                }
+                match lookup(&key) {
+                    Some(v) => out.push_str(v.as_ref()),
+                    None => return Err(TemplateError::UnknownPlaceholder(key, fmt.to_string())),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn gm() -> GameMode {
+        GameMode {
+            name: "Total Mayhem".into(),
+            players: "6v6".into(),
+            image: None,
+            description: Some("Power up and embrace the chaos.".into()),
+            label: Some("Daily".into()),
+        }
+    }
+
+    #[test]
+    fn render_placeholders() {
+        let t = Template {
+            title: Some("{label}: {name} ({players})".into()),
+            body: Some("{{{description}}} on {date}".into()),
+            ..Default::default()
+        };
+        let date = Utc.with_ymd_and_hms(2019, 8, 30, 0, 0, 0).unwrap();
+        assert_eq!(t.title(&gm(), date).unwrap(), "Daily: Total Mayhem (6v6)");
+        assert_eq!(
+            t.body(&gm(), date).unwrap().unwrap(),
+            "{Power up and embrace the chaos.} on 2019-08-30"
+        );
+    }
+
+    #[test]
+    fn validate_rejects_unknown_placeholder() {
+        let t = Template {
+            body: Some("{nmae}".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            t.validate(),
+            Err(TemplateError::UnknownPlaceholder(
+                "nmae".into(),
+                "{nmae}".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn default_body_is_description() {
+        let date = Utc.with_ymd_and_hms(2019, 8, 30, 0, 0, 0).unwrap();
+        let mut mode = gm();
+        let t = Template::default();
+        assert_eq!(
+            t.body(&mode, date).unwrap().unwrap(),
+            "Power up and embrace the chaos."
+        );
+        mode.description = None;
+        assert_eq!(t.body(&mode, date).unwrap(), None);
+    }
+
+    #[test]
+    fn colour() {
+        let mut mode = gm();
+        let mut t = Template::default();
+        assert_eq!(t.colour(&mode), Ok(DEFAULT_COLOUR));
+        mode.label = Some("Weekly".into());
+        assert_eq!(t.colour(&mode), Ok(WEEKLY_COLOUR));
+        t.colour = Some("#ff0000".into());
+        assert_eq!(t.colour(&mode), Ok(0xff_00_00));
+        t.colour = Some("red".into());
+        assert!(t.validate().is_err());
+    }
+}
@@ -4,8 +4,9 @@ extern crate log;
 use clap::{App, Arg};
 
 use failure::bail;
+use std::path::Path;
 
-use ow_arcade_watcher::settings::load;
+use ow_arcade_watcher::settings::load_from;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -15,7 +16,13 @@ fn run() -> Result<(), failure::Error> {
     let args = get_args();
     setup_logger(args.occurrences_of("verbosity"));
 
-    let cfg = load()?;
+    let overrides = args
+        .values_of("set")
+        .into_iter()
+        .flatten()
+        .map(parse_override)
+        .collect::<Result<Vec<_>, _>>()?;
+    let cfg = load_from(args.value_of_os("settings").map(Path::new), &overrides)?;
 
     trace!("Args: {:#?}", &args);
     trace!("Cfg: {:#?}", &cfg);
@@ -25,11 +32,25 @@ fn run() -> Result<(), failure::Error> {
         ("say", Some(sub_m)) => subcommand::say(sub_m, &cfg)?,
         ("config", Some(sub_m)) => match sub_m.subcommand() {
             ("validate", Some(sub_m)) => subcommand::validate(sub_m)?,
+            ("schema", Some(sub_m)) => subcommand::schema(sub_m)?,
+            ("show-effective", Some(sub_m)) => subcommand::show_effective(sub_m, &cfg)?,
             ("pull", Some(sub_m)) => subcommand::pull(sub_m, &cfg)?,
             ("push", Some(sub_m)) => subcommand::push(sub_m, &cfg)?,
+            ("edit", Some(sub_m)) => subcommand::edit(sub_m, &cfg)?,
             ("", _) => bail!("Please provide a command:\n{}", args.usage()),
             subc => bail!("Unknown command: config: {:?}\n{}", subc, args.usage()),
         },
+        ("state", Some(sub_m)) => match sub_m.subcommand() {
+            ("show", Some(sub_m)) => subcommand::show(sub_m, &cfg)?,
+            ("diff", Some(sub_m)) => subcommand::diff(sub_m, &cfg)?,
+            ("set", Some(sub_m)) => subcommand::set(sub_m, &cfg)?,
+            ("reset", Some(sub_m)) => subcommand::reset(sub_m, &cfg)?,
+            ("enable", Some(sub_m)) => subcommand::enable(sub_m, &cfg)?,
+            ("history", Some(sub_m)) => subcommand::history(sub_m, &cfg)?,
+            ("restore", Some(sub_m)) => subcommand::restore(sub_m, &cfg)?,
+            ("", _) => bail!("Please provide a command:\n{}", args.usage()),
+            subc => bail!("Unknown command: state: {:?}\n{}", subc, args.usage()),
+        },
         ("", _) => bail!("Please provide a command:\n{}", args.usage()),
         subc => bail!("Unknown command: {:?}\n{}", subc, args.usage()),
     }
@@ -37,9 +58,69 @@ fn run() -> Result<(), failure::Error> {
     Ok(())
 }
 
+/// Split a `--set KEY=VALUE` argument
+fn parse_override(arg: &str) -> Result<(String, String), failure::Error> {
+    match arg.find('=') {
+        Some(i) => Ok((arg[..i].to_string(), arg[i + 1..].to_string())),
+        None => bail!("expected KEY=VALUE, got {:?}", arg),
+    }
+}
+
 mod util {
+    use failure::bail;
     use serde::de::DeserializeOwned;
-    use std::{fs, path::Path};
+    use std::{
+        env, fs,
+        io::{self, Write},
+        path::Path,
+        process::Command,
+    };
+
+    /// Let the user edit `text` in `$VISUAL` or `$EDITOR`, returning the result
+    ///
+    /// Lines starting with `//` are blanked out, so they can carry notes for
+    /// the user without upsetting line numbers.
+    pub fn edit_file(path: &Path, text: &str) -> Result<String, failure::Error> {
+        fs::write(path, text)?;
+        let editor = env::var("VISUAL")
+            .or_else(|_| env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_string());
+        // through the shell, so an editor like `code --wait` works
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", editor))
+            .arg("sh")
+            .arg(path)
+            .status()?;
+        if !status.success() {
+            bail!(
+                "{} exited with {}, {} was kept",
+                editor,
+                status,
+                path.display()
+            );
+        }
+        Ok(fs::read_to_string(path)?
+            .lines()
+            .map(|line| {
+                if line.trim_start().starts_with("//") {
+                    ""
+                } else {
+                    line
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    /// Ask a yes/no question on the terminal
+    pub fn confirm(question: &str) -> Result<bool, failure::Error> {
+        print!("{} [y/N] ", question);
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+    }
 
     pub fn open_json_obj<D: DeserializeOwned, P: AsRef<Path>>(
         path: P,
@@ -115,6 +196,22 @@ fn get_args() -> clap::ArgMatches<'static> {
                 .global(true)
                 .help("Sets the level of verbosity"),
         )
+        .arg(
+            Arg::with_name("settings")
+                .long("settings")
+                .takes_value(true)
+                .global(true)
+                .help("Load settings from a TOML, YAML or JSON file"),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("Override a setting with KEY=VALUE, e.g. S3_BUCKET=foo"),
+        )
         .subcommand(
             clap::SubCommand::with_name("watcher")
                 .about("Check and update arcade status")
@@ -123,7 +220,19 @@ fn get_args() -> clap::ArgMatches<'static> {
                         .short("c")
                         .long("config")
                         .takes_value(true)
-                        .help("Provide a file with the program's configuration"),
+                        .help("Load the configuration from a file, a URL or store:KEY"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Show what would be announced, without sending or saving anything"),
+                )
+                .arg(
+                    Arg::with_name("from-state")
+                        .long("from-state")
+                        .takes_value(true)
+                        .requires("dry-run")
+                        .help("Compare the arcade to this gamestate instead of the stored one"),
                 ),
         )
         .subcommand(
@@ -159,7 +268,25 @@ fn get_args() -> clap::ArgMatches<'static> {
                                 .help("Provide a file with the program's configuration"),
                         ),
                 )
-                .subcommand(clap::SubCommand::with_name("pull").about("Grab the current config"))
+                .subcommand(
+                    clap::SubCommand::with_name("schema")
+                        .about("Print a JSON Schema for the config object, for editors"),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("show-effective")
+                        .about("Show the merged settings, and where each one came from"),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("pull")
+                        .about("Grab the current config")
+                        .arg(
+                            Arg::with_name("output")
+                                .short("o")
+                                .long("output")
+                                .takes_value(true)
+                                .help("Write it to a file, remembering the version for push"),
+                        ),
+                )
                 .subcommand(
                     clap::SubCommand::with_name("push")
                         .about("Push a new config")
@@ -169,6 +296,88 @@ fn get_args() -> clap::ArgMatches<'static> {
                                 .index(1)
                                 .takes_value(true)
                                 .help("Provide a file with the program's configuration"),
+                        )
+                        .arg(
+                            Arg::with_name("yes")
+                                .short("y")
+                                .long("yes")
+                                .help("Push without asking for confirmation"),
+                        )
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .help("Push without checking the remote is still what was pulled"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("edit")
+                        .about("Pull the config, edit it in $EDITOR, validate it and push it")
+                        .arg(
+                            Arg::with_name("yes")
+                                .short("y")
+                                .long("yes")
+                                .help("Push without asking for confirmation"),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("state")
+                .about("Inspect and fix the watcher's state")
+                .subcommand(
+                    clap::SubCommand::with_name("show")
+                        .about("Print the stored gamestate")
+                        .arg(
+                            Arg::with_name("outbox")
+                                .long("outbox")
+                                .help("Print the undelivered notifications instead"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("diff")
+                        .about("Compare the stored gamestate to the live arcade"),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("set")
+                        .about("Replace the stored gamestate")
+                        .arg(
+                            Arg::with_name("state")
+                                .required(true)
+                                .index(1)
+                                .takes_value(true)
+                                .help("A file in the format printed by `state show`"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("reset")
+                        .about("Mark the live arcade as announced and drop pending notifications")
+                        .arg(Arg::with_name("empty").long("empty").help(
+                            "Forget the arcade instead, so everything in it is announced again",
+                        )),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("enable")
+                        .about("Forget a target's failures, so it is delivered to again")
+                        .arg(
+                            Arg::with_name("target")
+                                .required(true)
+                                .index(1)
+                                .takes_value(true)
+                                .help("Like discord:1234 or push:phone"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("history")
+                        .about("List the versions of the state we can restore"),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("restore")
+                        .about("Put the state back the way it was at some version")
+                        .arg(
+                            Arg::with_name("version")
+                                .required(true)
+                                .index(1)
+                                .takes_value(true)
+                                .help("A version from `state history`"),
                         ),
                 ),
         )
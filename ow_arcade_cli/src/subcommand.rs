@@ -1,11 +1,46 @@
-use crate::util::open_json_obj;
+use crate::util::{confirm, edit_file, open_json_obj};
+use chrono::offset::Utc;
 use clap::ArgMatches;
+use failure::bail;
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+use stupids3::{Expect, ObjectStore, StupidS3Error, Versioned};
 
-use ow_arcade_watcher::{settings::ArcadeBotConfig, watch_and_update, DynamicConfig};
-use stupids3::{get, put};
+use overwatch::{
+    arcade_state::{ArcadeState, GameState, Outbox, S3State},
+    arcade_watcher::Target,
+    owatapi::fetch_arcade,
+};
+use ow_arcade_watcher::{
+    diff::config_diff,
+    dry_run,
+    schema::{config_schema, parse_config},
+    settings::ArcadeBotConfig,
+    source::ConfigSource,
+    watch_and_update, DynamicConfig,
+};
 
-pub fn watcher(_args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
-    watch_and_update(cfg)
+pub fn watcher(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    let source = match args.value_of("config") {
+        Some(source) => source.parse::<ConfigSource>()?,
+        None => cfg.config_source()?,
+    };
+    if args.is_present("dry-run") {
+        let from_state = match args.value_of_os("from-state") {
+            Some(path) => Some(open_json_obj::<GameState, _>(path)?),
+            None => None,
+        };
+        println!("{}", dry_run(cfg, &source, from_state)?);
+        return Ok(());
+    }
+    let report = watch_and_update(cfg, &source)?;
+    println!("{}", report);
+    Ok(())
 }
 
 pub fn say(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
@@ -21,19 +56,294 @@ pub fn say(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Erro
 }
 
 pub fn validate(args: &ArgMatches) -> Result<(), failure::Error> {
-    let cfg: DynamicConfig = open_json_obj(args.value_of_os("config").unwrap())?;
-    println!("{:#?}", cfg);
+    let path = args.value_of_os("config").unwrap();
+    let cfg = parse_config(&fs::read_to_string(path)?)?;
+    cfg.validate()?;
+    println!("{:#?}", cfg.expanded()?);
+    Ok(())
+}
+
+pub fn schema(_args: &ArgMatches) -> Result<(), failure::Error> {
+    println!("{}", serde_json::to_string_pretty(&config_schema())?);
+    Ok(())
+}
+
+pub fn show_effective(_args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    for setting in cfg.effective()? {
+        println!("{}", setting);
+    }
     Ok(())
 }
 
+/// Where `config pull --output` records the version it pulled
+fn etag_path(config: &OsStr) -> PathBuf {
+    let mut path = OsString::from(config);
+    path.push(".etag");
+    path.into()
+}
+
+/// The config in the store, if there is one
+fn fetch_config(
+    store: &dyn ObjectStore,
+    key: &str,
+) -> Result<Option<Versioned<Vec<u8>>>, failure::Error> {
+    match store.get_bytes(key) {
+        Ok(v) => Ok(Some(v)),
+        Err(StupidS3Error::NoSuchKey { .. }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Only an invalid remote config is worth replacing without seeing it
+fn parse_remote(raw: &[u8]) -> Option<DynamicConfig> {
+    match serde_json::from_slice(raw) {
+        Ok(remote) => Some(remote),
+        Err(e) => {
+            warn!("the remote config is invalid, it will be replaced: {}", e);
+            None
+        }
+    }
+}
+
+/// Print the changes and push them once confirmed, returning the new ETag
+///
+/// Returns `None` when there was nothing to push.
+fn confirm_push(
+    store: &dyn ObjectStore,
+    key: &str,
+    remote: Option<&DynamicConfig>,
+    etag: Option<&str>,
+    dyncfg: &DynamicConfig,
+    yes: bool,
+) -> Result<Option<String>, failure::Error> {
+    let changes = config_diff(remote, dyncfg)?;
+    if changes.is_empty() {
+        println!("nothing has changed");
+        return Ok(None);
+    }
+    for change in &changes {
+        println!("{}", change);
+    }
+    if !yes && !confirm("push these changes?")? {
+        bail!("push cancelled");
+    }
+
+    let expect = match etag {
+        Some(etag) => Expect::ETag(etag),
+        None => Expect::Missing,
+    };
+    Ok(Some(store.put_if_match(key, dyncfg, expect)?))
+}
+
+/// Show what would change, and only push if it is confirmed and nobody else
+/// has pushed since this config was pulled
 pub fn push(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
-    let dyncfg: DynamicConfig = open_json_obj(args.value_of_os("config").unwrap())?;
-    put(cfg.s3_bucket()?, cfg.s3_key_config()?, &dyncfg)?;
+    let path = args.value_of_os("config").unwrap();
+    let dyncfg = parse_config(&fs::read_to_string(path)?)?;
+    let store = cfg.store()?;
+    let key = cfg.s3_key_config()?;
+
+    let current = fetch_config(store.as_ref(), &key)?;
+    let etag = current.as_ref().map(|v| v.etag.as_str());
+    if !args.is_present("force") {
+        match fs::read_to_string(etag_path(path)) {
+            Ok(ref pulled) if Some(pulled.trim()) != etag => {
+                bail!("the remote config has changed since it was pulled, pull it again or use --force")
+            }
+            Ok(_) => {}
+            // with nothing there yet, there is nothing to overwrite
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && etag.is_none() => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => bail!(
+                "it isn't known which version {} was pulled from, so it could undo someone else's changes, pull it with --output or use --force",
+                Path::new(path).display()
+            ),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let remote = current.as_ref().and_then(|v| parse_remote(&v.value));
+    let yes = args.is_present("yes");
+    if let Some(etag) = confirm_push(store.as_ref(), &key, remote.as_ref(), etag, &dyncfg, yes)? {
+        fs::write(etag_path(path), etag)?;
+    }
+    Ok(())
+}
+
+/// Parse and validate an edited config
+fn check_config(text: &str) -> Result<DynamicConfig, failure::Error> {
+    let dyncfg = parse_config(text)?;
+    dyncfg.validate()?;
+    Ok(dyncfg)
+}
+
+/// Pull the config, edit it until it is valid, then push it
+pub fn edit(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    let store = cfg.store()?;
+    let key = cfg.s3_key_config()?;
+    let current = match fetch_config(store.as_ref(), &key)? {
+        Some(current) => current,
+        None => bail!("there is no config at {} to edit, push one first", key),
+    };
+
+    let path = env::temp_dir().join(format!("owarcadebot-config-{}.json", process::id()));
+    // as if it had been pulled, so `config push` can pick up where we leave off
+    let etag = etag_path(path.as_os_str());
+    fs::write(&etag, &current.etag)?;
+    let mut text = String::from_utf8(current.value.clone())?;
+    let dyncfg = loop {
+        let edited = edit_file(&path, &text)?;
+        if edited.trim().is_empty() {
+            fs::remove_file(&path)?;
+            fs::remove_file(&etag)?;
+            bail!("edit cancelled, the config was left empty");
+        }
+        match check_config(&edited) {
+            Ok(dyncfg) => {
+                // without the errors from earlier attempts, so it can be pushed by hand
+                fs::write(&path, format!("{}\n", edited.trim_end()))?;
+                break dyncfg;
+            }
+            Err(e) => {
+                // the error goes at the end as comments, which edit_file blanks
+                // out again, so line numbers still match what the editor shows
+                text = format!(
+                    "{}\n\n// The config above is invalid, fix it or save an empty file to give up.\n",
+                    edited.trim_end()
+                );
+                for cause in e.iter_chain() {
+                    text.push_str(&format!("// error: {}\n", cause));
+                }
+            }
+        }
+    };
+
+    let remote = parse_remote(&current.value);
+    let yes = args.is_present("yes");
+    confirm_push(
+        store.as_ref(),
+        &key,
+        remote.as_ref(),
+        Some(current.etag.as_str()),
+        &dyncfg,
+        yes,
+    )
+    .map_err(|e| match e.downcast::<StupidS3Error>() {
+        Ok(StupidS3Error::PreconditionFailed { .. }) => {
+            failure::format_err!("the remote config changed while it was being edited")
+        }
+        Ok(e) => e.into(),
+        Err(e) => e,
+    })
+    .map_err(|e| e.context(format!("the edited config was kept in {}", path.display())))?;
+    fs::remove_file(&path)?;
+    fs::remove_file(&etag)?;
+    Ok(())
+}
+
+fn state(cfg: &ArcadeBotConfig) -> Result<S3State, failure::Error> {
+    Ok(
+        S3State::new(cfg.store()?, cfg.s3_key_gamestate()?, cfg.s3_key_outbox()?)
+            .keep_history(cfg.state_history()?),
+    )
+}
+
+pub fn show(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    let state = state(cfg)?;
+    if args.is_present("outbox") {
+        println!("{}", serde_json::to_string_pretty(&state.outbox()?)?);
+        return Ok(());
+    }
+    match state.game_state()? {
+        Some(gs) => println!("{}", serde_json::to_string_pretty(&gs)?),
+        None => println!("no gamestate has been written yet"),
+    }
+    Ok(())
+}
+
+/// What the next run would consider new or gone, compared to the live arcade
+pub fn diff(_args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    let arcade = fetch_arcade()?;
+    let diff = state(cfg)?.mode_diff(arcade.modes.iter())?;
+    let mut lines = diff
+        .added
+        .iter()
+        .map(|gm| format!("+ {}", gm))
+        .chain(diff.removed.iter().map(|gm| format!("- {}", gm)))
+        .collect::<Vec<_>>();
+    lines.sort_by(|a, b| a[2..].cmp(&b[2..]));
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+pub fn set(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    let gs: GameState = open_json_obj(args.value_of_os("state").unwrap())?;
+    let mut new = GameState::new(gs.modes, gs.created_at.unwrap_or_else(Utc::now));
+    new.gone_since = gs.gone_since;
+    state(cfg)?.set_game_state(&new)?;
+    Ok(())
+}
+
+/// Mark everything in the arcade as already announced, and drop anything pending
+///
+/// With `--empty`, forget everything instead, so the next run announces it all.
+pub fn reset(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    let mut state = state(cfg)?;
+    if args.is_present("empty") {
+        state.set_modes(std::iter::empty(), Utc::now())?;
+    } else {
+        let arcade = fetch_arcade()?;
+        state.set_modes(arcade.modes.iter(), arcade.created_at)?;
+    }
+    // keep target health, so disabled targets stay disabled until `state enable`
+    let outbox = state.outbox()?;
+    state.set_outbox(&Outbox {
+        pending: Vec::new(),
+        targets: outbox.targets,
+    })?;
+    Ok(())
+}
+
+/// Clear a target's health, re-enabling it if it was disabled
+pub fn enable(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    let target = args.value_of("target").unwrap().parse::<Target>()?;
+    let mut state = state(cfg)?;
+    let mut outbox = state.outbox()?;
+    match outbox.targets.remove(&target) {
+        Some(health) if health.disabled => println!("enabled {}", target),
+        Some(_) => println!("cleared the failures recorded for {}", target),
+        None => {
+            println!("{} is not disabled", target);
+            return Ok(());
+        }
+    }
+    state.set_outbox(&outbox)?;
+    Ok(())
+}
+
+pub fn history(_args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    for v in state(cfg)?.history()? {
+        println!("{}", v);
+    }
+    Ok(())
+}
+
+pub fn restore(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    for v in state(cfg)?.restore(args.value_of("version").unwrap())? {
+        println!("restored {}", v);
+    }
     Ok(())
 }
 
-pub fn pull(_args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
-    let raw_json = get(cfg.s3_bucket()?, cfg.s3_key_config()?)?;
-    println!("{}", raw_json);
+pub fn pull(args: &ArgMatches, cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+    let raw = cfg.store()?.get_bytes(&cfg.s3_key_config()?)?;
+    match args.value_of_os("output") {
+        Some(path) => {
+            fs::write(path, &raw.value)?;
+            fs::write(etag_path(path), &raw.etag)?;
+        }
+        None => println!("{}", String::from_utf8(raw.value)?),
+    }
     Ok(())
 }
@@ -24,9 +24,12 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 fn my_handler(_e: CustomEvent, _c: Context) -> Result<CustomOutput, HandlerError> {
     let cfg = load()?;
-    watch_and_update(&cfg)?;
+    let report = watch_and_update(&cfg, &cfg.config_source()?)?;
+    if report.failures() > 0 {
+        log::error!("some notifications failed:\n{}", report);
+    }
     Ok(CustomOutput {
-        message: "success".to_string(),
+        message: report.to_string(),
     })
 }
 
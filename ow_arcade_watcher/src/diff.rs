@@ -0,0 +1,162 @@
+use crate::DynamicConfig;
+use serde_json::{Map, Value};
+use std::{collections::BTreeSet, fmt};
+
+/// One difference between two configs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added(String),
+    Removed(String),
+    /// Something that exists in both, with what changed about it
+    Changed(String, Vec<String>),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added(what) => write!(f, "+ {}", what),
+            Change::Removed(what) => write!(f, "- {}", what),
+            Change::Changed(what, details) => write!(f, "~ {}: {}", what, details.join(", ")),
+        }
+    }
+}
+
+/// What pushing `new` over `old` would change, room by room and subscription
+/// by subscription
+pub fn config_diff(
+    old: Option<&DynamicConfig>,
+    new: &DynamicConfig,
+) -> Result<Vec<Change>, failure::Error> {
+    let old = match old {
+        Some(old) => serde_json::to_value(old)?,
+        None => Value::Null,
+    };
+    let new = serde_json::to_value(new)?;
+    let mut changes = Vec::new();
+    diff_entries(
+        "room",
+        &old["watcher"]["rooms"],
+        &new["watcher"]["rooms"],
+        &mut changes,
+    );
+    diff_entries("push", &old["push"], &new["push"], &mut changes);
+    diff_entries("group", &old["groups"], &new["groups"], &mut changes);
+
+    let empty = Map::new();
+    let old_watcher = old["watcher"].as_object().unwrap_or(&empty);
+    let new_watcher = new["watcher"].as_object().unwrap_or(&empty);
+    let settings = changed_fields(old_watcher, new_watcher)
+        .into_iter()
+        .filter(|field| field != "rooms")
+        .collect::<Vec<_>>();
+    if !settings.is_empty() {
+        changes.push(Change::Changed("watcher".to_string(), settings));
+    }
+    Ok(changes)
+}
+
+/// Compare two maps of named things, like rooms by ID
+fn diff_entries(kind: &str, old: &Value, new: &Value, changes: &mut Vec<Change>) {
+    let empty = Map::new();
+    let old = old.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+    let names = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+    for name in names {
+        let what = match new
+            .get(name)
+            .or_else(|| old.get(name))
+            .and_then(|v| v["name"].as_str())
+        {
+            Some(label) => format!("{} {} ({})", kind, name, label),
+            None => format!("{} {}", kind, name),
+        };
+        match (old.get(name), new.get(name)) {
+            (None, Some(_)) => changes.push(Change::Added(what)),
+            (Some(_), None) => changes.push(Change::Removed(what)),
+            (Some(Value::Object(o)), Some(Value::Object(n))) if o != n => {
+                let details = changed_fields(o, n)
+                    .into_iter()
+                    .map(|field| match field.as_str() {
+                        "gamemodes" => gamemode_changes(&o[&field], &n[&field]),
+                        _ => field,
+                    })
+                    .collect();
+                changes.push(Change::Changed(what, details))
+            }
+            // subscription groups are just lists of gamemodes
+            (Some(o @ Value::Array(_)), Some(n)) if o != n => {
+                changes.push(Change::Changed(what, vec![gamemode_changes(o, n)]))
+            }
+            _ => {}
+        }
+    }
+}
+
+fn changed_fields(old: &Map<String, Value>, new: &Map<String, Value>) -> Vec<String> {
+    old.keys()
+        .chain(new.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|k| old.get(*k) != new.get(*k))
+        .cloned()
+        .collect()
+}
+
+/// Like `gamemodes +g4 -g1`
+fn gamemode_changes(old: &Value, new: &Value) -> String {
+    let modes = |v: &Value| {
+        v.as_array()
+            .into_iter()
+            .flatten()
+            .map(|gm| {
+                let name = gm["name"].as_str().unwrap_or_default();
+                let players = gm["players"].as_str().unwrap_or_default();
+                (name.to_string(), players.to_string())
+            })
+            .collect::<BTreeSet<_>>()
+    };
+    let (old, new) = (modes(old), modes(new));
+    let mut summary = "gamemodes".to_string();
+    for (name, _) in new.difference(&old) {
+        summary.push_str(&format!(" +{}", name));
+    }
+    for (name, _) in old.difference(&new) {
+        summary.push_str(&format!(" -{}", name));
+    }
+    summary
+}
+
+#[cfg(test)]
+mod test {
+    use super::{config_diff, Change};
+    use crate::DynamicConfig;
+
+    const EXAMPLE_CFG: &str = include_str!("../example_watcher_config.json");
+
+    #[test]
+    fn semantic_diff() {
+        let old: DynamicConfig = serde_json::from_str(EXAMPLE_CFG).unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(EXAMPLE_CFG).unwrap();
+        value["watcher"]["rooms"]
+            .as_object_mut()
+            .unwrap()
+            .remove("5678");
+        value["watcher"]["rooms"]["1234"]["gamemodes"][0]["name"] = "g4".into();
+        value["watcher"]["delivery_attempts"] = 3.into();
+        value["push"]["phone"]["priority"] = 5.into();
+        let new: DynamicConfig = serde_json::from_value(value).unwrap();
+
+        assert_eq!(
+            config_diff(Some(&old), &new).unwrap(),
+            vec![
+                Change::Changed("room 1234".into(), vec!["gamemodes +g4 -g1".into()]),
+                Change::Removed("room 5678".into()),
+                Change::Changed("push phone".into(), vec!["priority".into()]),
+                Change::Changed("watcher".into(), vec!["delivery_attempts".into()]),
+            ]
+        );
+        assert!(config_diff(Some(&old), &old).unwrap().is_empty());
+        // two rooms, two subscriptions and the translations
+        assert_eq!(config_diff(None, &old).unwrap().len(), 5);
+    }
+}
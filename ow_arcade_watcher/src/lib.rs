@@ -1,39 +1,410 @@
+#[macro_use]
+extern crate log;
+
 use crate::settings::ArcadeBotConfig;
 
+use crate::{preview::Preview, push::PushSubscription, report::RunReport, source::ConfigSource};
+use chrono::{offset::Utc, DateTime};
+use failure::Fail;
 use overwatch::{
-    arcade_state::S3State,
-    arcade_watcher::{Watcher, WatcherConfig},
+    arcade_state::{ArcadeState, GameState, Notification, Outbox, S3State},
+    arcade_watcher::{expand_groups, Target, Watcher, WatcherConfig},
+    i18n::{validate_locale, Translator},
+    owatapi::fetch_arcade,
+    template::Template,
+    GameMode,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use stupids3::get_obj;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use stupids3::{MemoryStore, StupidS3Error};
 
-pub fn watch_and_update(cfg: &ArcadeBotConfig) -> Result<(), failure::Error> {
+/// Check the arcade and announce anything new, returning what was delivered
+///
+/// A failure to deliver to one target doesn't stop us delivering to the rest,
+/// so look at the report to see if everything made it.
+pub fn watch_and_update(
+    cfg: &ArcadeBotConfig,
+    source: &ConfigSource,
+) -> Result<RunReport, failure::Error> {
     let discord_client = discord::create_client(&cfg.discord_token()?)?;
-    let s3_bucket = cfg.s3_bucket()?;
-    let bot_cfg: DynamicConfig = get_obj(&s3_bucket, cfg.s3_key_config()?)?;
-    let mut watcher = Watcher::new(
-        S3State {
-            bucket: s3_bucket,
-            keyname: cfg.s3_key_gamestate()?,
-        },
-        &bot_cfg.watcher,
+    let store = cfg.store()?;
+    let bot_cfg = source.load(&*store)?.expanded()?;
+    let mut watcher = watcher(
+        &bot_cfg,
+        S3State::new(store, cfg.s3_key_gamestate()?, cfg.s3_key_outbox()?)
+            .keep_history(cfg.state_history()?),
+    );
+    let update = watcher.update(fetch_arcade()?)?;
+    if let Err(e) = watcher.claim(&update) {
+        if let Some(StupidS3Error::PreconditionFailed { .. }) = e.downcast_ref::<StupidS3Error>() {
+            warn!("another run has already claimed this update: {}", e);
+            return Ok(RunReport::default());
+        }
+        return Err(e);
+    }
+
+    let disable_after = bot_cfg.watcher.disable_after();
+    let mut report = RunReport::default();
+    let (due, held) = hold_quiet(&update.notifications);
+    for n in &held {
+        if let Some(until) = n.not_before {
+            info!(
+                "holding {} for {} until {}, it is quiet there",
+                n.gamemode, n.target, until
+            );
+        }
+    }
+    let mut outbox = Outbox {
+        pending: held,
+        targets: update.targets.clone(),
+    };
+    deliver_all(
+        &due,
+        &mut outbox,
+        &mut report,
+        disable_after,
+        |target, batch| deliver(&discord_client, &bot_cfg, target, batch),
     );
-    for (channel, new_gamemodes) in watcher.update()? {
-        for gm in new_gamemodes {
-            discord::send_gamemode(&discord_client, channel, &gm)?;
+
+    watcher.commit(outbox)?;
+    Ok(report)
+}
+
+/// Why some of a batch wasn't delivered
+struct Undelivered {
+    /// How many of the batch, in order, went out before the failure
+    sent: usize,
+    permanent: bool,
+    error: failure::Error,
+}
+
+/// Send each target its batch, requeueing what didn't make it
+///
+/// A target's health is updated once per run, however many notifications it
+/// had waiting.
+fn deliver_all<F>(
+    due: &[Notification],
+    outbox: &mut Outbox,
+    report: &mut RunReport,
+    disable_after: u32,
+    mut send: F,
+) where
+    F: FnMut(&Target, &[&Notification]) -> Result<(), Undelivered>,
+{
+    for (target, batch) in batches(due) {
+        let health = outbox.targets.entry(target.clone()).or_default();
+        if health.disabled {
+            warn!(
+                "dropping {} notifications for {}, it is disabled",
+                batch.len(),
+                target
+            );
+            for n in &batch {
+                report.skipped(target, &n.gamemode);
+            }
+            continue;
+        }
+        match send(target, &batch) {
+            Ok(()) => {
+                outbox.targets.remove(target);
+                for n in &batch {
+                    report.delivered(target, &n.gamemode);
+                }
+            }
+            Err(Undelivered {
+                sent,
+                permanent,
+                error,
+            }) => {
+                error!("could not deliver to {}: {}", target, error);
+                if health.record_failure(error.to_string(), permanent, disable_after) {
+                    error!("disabling {} after repeated permanent failures", target);
+                    report.disabled(target);
+                }
+                let (sent, unsent) = batch.split_at(sent.min(batch.len()));
+                for n in sent {
+                    report.delivered(target, &n.gamemode);
+                }
+                for n in unsent {
+                    report.failed(target, &n.gamemode, &error);
+                    if !health.disabled {
+                        let mut n = (*n).clone();
+                        n.attempts += 1;
+                        outbox.pending.push(n);
+                    }
+                }
+            }
         }
     }
-    Ok(())
+    outbox
+        .targets
+        .retain(|_, health| health.disabled || health.last_error.is_some());
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Check the arcade, but only show what would be delivered
+///
+/// Nothing is written or sent. With `from_state`, the arcade is compared to
+/// that gamestate, with nothing left over from earlier runs, instead of what
+/// is stored.
+pub fn dry_run(
+    cfg: &ArcadeBotConfig,
+    source: &ConfigSource,
+    from_state: Option<GameState>,
+) -> Result<Preview, failure::Error> {
+    let store = cfg.store()?;
+    let bot_cfg = source.load(&*store)?.expanded()?;
+    let state = match from_state {
+        Some(gs) => {
+            let mut state = S3State::new(
+                Box::new(MemoryStore::default()),
+                cfg.s3_key_gamestate()?,
+                cfg.s3_key_outbox()?,
+            )
+            .keep_history(0);
+            state.set_game_state(&gs)?;
+            state
+        }
+        None => S3State::new(store, cfg.s3_key_gamestate()?, cfg.s3_key_outbox()?).read_only(),
+    };
+    let update = watcher(&bot_cfg, state).update(fetch_arcade()?)?;
+
+    let mut preview = Preview::default();
+    let (due, held) = hold_quiet(&update.notifications);
+    for n in &held {
+        if let Some(until) = n.not_before {
+            preview.held(&n.target, until);
+        }
+    }
+    for (target, batch) in batches(&due) {
+        if matches!(update.targets.get(target), Some(health) if health.disabled) {
+            preview.skipped(target);
+            continue;
+        }
+        for rendered in render(&bot_cfg, target, &batch)? {
+            preview.message(target, rendered);
+        }
+    }
+    Ok(preview)
+}
+
+fn watcher<T: ArcadeState>(bot_cfg: &DynamicConfig, state: T) -> Watcher<T> {
+    let mut watcher = Watcher::new(state, &bot_cfg.watcher);
+    for (name, sub) in &bot_cfg.push {
+        watcher.watch(Target::Push(name.clone()), sub.gamemodes.iter());
+    }
+    watcher
+}
+
+fn is_permanent_error(target: &Target, err: &failure::Error) -> bool {
+    match target {
+        Target::Discord(_) => discord::is_permanent_error(err),
+        Target::Push(_) => pushnotify::is_permanent_error(err),
+    }
+}
+
+/// Split notifications into those we can deliver now and those waiting out
+/// quiet hours
+fn hold_quiet(notifications: &[Notification]) -> (Vec<Notification>, Vec<Notification>) {
+    let now = Utc::now();
+    notifications.iter().cloned().partition(|n| n.is_due(now))
+}
+
+/// Group notifications by target, keeping their order
+fn batches(notifications: &[Notification]) -> Vec<(&Target, Vec<&Notification>)> {
+    let mut batches: Vec<(&Target, Vec<&Notification>)> = Vec::new();
+    for n in notifications {
+        match batches.iter_mut().find(|(t, _)| *t == &n.target) {
+            Some((_, batch)) => batch.push(n),
+            None => batches.push((&n.target, vec![n])),
+        }
+    }
+    batches
+}
+
+/// Where a target's notifications go, and how they are laid out
+enum Destination<'a> {
+    Discord {
+        channel: u64,
+        template: Template,
+        tr: Translator<'a>,
+    },
+    Push {
+        sub: &'a PushSubscription,
+        tr: Translator<'a>,
+    },
+}
+
+/// Look up a target in the config, `None` if it is no longer configured
+///
+/// Both `render` and `deliver` go through here, so a preview can't differ
+/// from what is sent.
+fn destination<'a>(bot_cfg: &'a DynamicConfig, target: &Target) -> Option<Destination<'a>> {
+    match *target {
+        Target::Discord(channel) => {
+            let room = bot_cfg.watcher.room(channel)?;
+            Some(Destination::Discord {
+                channel,
+                template: room.template().cloned().unwrap_or_default(),
+                tr: bot_cfg.watcher.translator(room.locale()),
+            })
+        }
+        Target::Push(ref name) => {
+            let sub = bot_cfg.push.get(name)?;
+            Some(Destination::Push {
+                sub,
+                tr: bot_cfg.watcher.translator(sub.locale.as_deref()),
+            })
+        }
+    }
+}
+
+fn dated<'a>(batch: &[&'a Notification]) -> Vec<(&'a GameMode, DateTime<Utc>)> {
+    batch.iter().map(|n| (&n.gamemode, n.created_at)).collect()
+}
+
+/// Render a batch the way `deliver` would send it
+fn render(
+    bot_cfg: &DynamicConfig,
+    target: &Target,
+    batch: &[&Notification],
+) -> Result<Vec<String>, failure::Error> {
+    match destination(bot_cfg, target) {
+        Some(Destination::Discord { template, tr, .. }) => {
+            Ok(discord::render_gamemodes(&dated(batch), &template, &tr)?
+                .iter()
+                .map(|embed| format!("{:#}", embed))
+                .collect())
+        }
+        Some(Destination::Push { tr, .. }) => Ok(batch
+            .iter()
+            .map(|n| {
+                let (title, body) = push::render(&n.gamemode, &tr);
+                format!("{}\n{}", title, body)
+            })
+            .collect()),
+        None => Ok(vec!["(no longer configured, would be dropped)".into()]),
+    }
+}
+
+/// Send a target its batch, with as few messages as it allows
+///
+/// Discord can take every notification for a channel in one go, push
+/// services get them one at a time.
+fn deliver(
+    discord_client: &discord::Client,
+    bot_cfg: &DynamicConfig,
+    target: &Target,
+    batch: &[&Notification],
+) -> Result<(), Undelivered> {
+    let undelivered = |sent, error| Undelivered {
+        sent,
+        permanent: is_permanent_error(target, &error),
+        error,
+    };
+    match destination(bot_cfg, target) {
+        Some(Destination::Discord {
+            channel,
+            template,
+            tr,
+        }) => discord::send_gamemodes(discord_client, channel, &dated(batch), &template, &tr)
+            .map_err(|e| {
+                let sent = e
+                    .downcast_ref::<discord::PartialDelivery>()
+                    .map_or(0, |p| p.delivered);
+                undelivered(sent, e)
+            }),
+        Some(Destination::Push { sub, tr }) => {
+            for (sent, n) in batch.iter().enumerate() {
+                push::send_gamemode(sub, &n.gamemode, &tr).map_err(|e| undelivered(sent, e))?;
+            }
+            Ok(())
+        }
+        None => {
+            warn!(
+                "dropping notifications for {}, it is no longer configured",
+                target
+            );
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct DynamicConfig {
     pub watcher: WatcherConfig,
+    #[serde(default)]
+    pub push: HashMap<String, PushSubscription>,
+    /// Named lists of gamemodes that rooms and push subscriptions can share
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub groups: HashMap<String, Vec<GameMode>>,
+    /// Fields we don't understand, kept so they survive being pulled and pushed
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+impl DynamicConfig {
+    /// A copy where every group reference is replaced by the group's gamemodes
+    pub fn expanded(&self) -> Result<DynamicConfig, failure::Error> {
+        let mut cfg = self.clone();
+        cfg.watcher.expand_groups(&self.groups)?;
+        for (name, sub) in &mut cfg.push {
+            let names = std::mem::take(&mut sub.groups);
+            expand_groups(&mut sub.gamemodes, &names, &self.groups)
+                .map_err(|e| e.context(format!("push {}", name)))?;
+        }
+        Ok(cfg)
+    }
+
+    /// Where each field we don't understand is, like `push.phone.colour`
+    pub fn unknown_fields(&self) -> Vec<String> {
+        let mut fields = self.extra.keys().cloned().collect::<Vec<_>>();
+        fields.extend(
+            self.watcher
+                .unknown_fields()
+                .into_iter()
+                .map(|f| format!("watcher.{}", f)),
+        );
+        for (name, sub) in &self.push {
+            fields.extend(sub.extra.keys().map(|k| format!("push.{}.{}", name, k)));
+        }
+        fields.sort();
+        fields
+    }
+
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        for field in self.unknown_fields() {
+            warn!("ignoring unknown field {}", field);
+        }
+        self.expanded()?;
+        let used = self
+            .watcher
+            .rooms()
+            .flat_map(|(_, rc)| rc.groups())
+            .chain(self.push.values().flat_map(|sub| &sub.groups))
+            .collect::<HashSet<_>>();
+        for name in self.groups.keys().filter(|name| !used.contains(name)) {
+            warn!("subscription group {:?} is not used", name);
+        }
+        self.watcher.validate()?;
+        for (name, sub) in &self.push {
+            if let Some(ref locale) = sub.locale {
+                validate_locale(locale).map_err(|e| e.context(format!("push {}", name)))?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::DynamicConfig;
+    use crate::{deliver_all, report::RunReport, DynamicConfig, Undelivered};
+    use chrono::offset::Utc;
+    use overwatch::{
+        arcade_state::{Notification, Outbox},
+        arcade_watcher::Target,
+        GameMode,
+    };
 
     const EXAMPLE_CFG: &str = include_str!("../example_watcher_config.json");
 
@@ -41,6 +412,113 @@ mod test {
     fn deserialize_config() {
         let _a: DynamicConfig = serde_json::from_str(EXAMPLE_CFG).unwrap();
     }
+
+    #[test]
+    fn validate_config() {
+        let a: DynamicConfig = serde_json::from_str(EXAMPLE_CFG).unwrap();
+        a.validate().unwrap();
+    }
+
+    #[test]
+    fn groups_are_expanded() {
+        let mut cfg: DynamicConfig = serde_json::from_value(serde_json::json!({
+            "watcher": {"rooms": {
+                "1": {"groups": ["dm"], "gamemodes": [{"name": "ctf", "players": "6v6"}]},
+                "2": {"groups": ["dm"]}
+            }},
+            "groups": {"dm": [
+                {"name": "ctf", "players": "6v6"},
+                {"name": "deathmatch", "players": "8 players"}
+            ]}
+        }))
+        .unwrap();
+        cfg.validate().unwrap();
+        let expanded = cfg.expanded().unwrap();
+        let names = |room| {
+            expanded
+                .watcher
+                .room(room)
+                .unwrap()
+                .gamemodes()
+                .iter()
+                .map(|gm| gm.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(1), vec!["ctf", "deathmatch"]);
+        assert_eq!(names(2), vec!["ctf", "deathmatch"]);
+
+        cfg.groups.clear();
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn health_is_updated_once_per_target() {
+        let phone = Target::Push("phone".into());
+        let due = ["a", "b", "c"]
+            .iter()
+            .map(|name| Notification {
+                target: phone.clone(),
+                gamemode: GameMode {
+                    name: name.to_string(),
+                    players: "6v6".into(),
+                    image: None,
+                    description: None,
+                    label: None,
+                },
+                created_at: Utc::now(),
+                attempts: 0,
+                not_before: None,
+            })
+            .collect::<Vec<_>>();
+        let mut outbox = Outbox::default();
+        let mut report = RunReport::default();
+        let mut calls = 0;
+        deliver_all(&due, &mut outbox, &mut report, 3, |_, batch| {
+            calls += 1;
+            assert_eq!(batch.len(), 3);
+            Err(Undelivered {
+                sent: 1,
+                permanent: true,
+                error: failure::err_msg("401 Unauthorized"),
+            })
+        });
+        assert_eq!(calls, 1);
+        let health = &outbox.targets[&phone];
+        assert_eq!(health.permanent_failures, 1);
+        assert!(!health.disabled);
+        let pending = outbox
+            .pending
+            .iter()
+            .map(|n| n.gamemode.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(pending, vec!["b", "c"]);
+        assert_eq!(report.failures(), 2);
+    }
+
+    #[test]
+    fn unknown_fields_survive_roundtrip() {
+        let json = serde_json::json!({
+            "owner": "me",
+            "watcher": {"rooms": {"1": {"gamemodes": [], "colour": "red"}}},
+            "push": {"phone": {
+                "service": "ntfy", "url": "https://ntfy.sh", "topic": "t", "token": null,
+                "priority": null, "tags": [], "locale": null, "gamemodes": [], "sound": "ding"
+            }}
+        });
+        let cfg: DynamicConfig = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(
+            cfg.unknown_fields(),
+            vec!["owner", "push.phone.sound", "watcher.rooms.1.colour"]
+        );
+        assert_eq!(serde_json::to_value(&cfg).unwrap(), json);
+    }
 }
 
+pub mod diff;
+pub mod preview;
+pub mod push;
+pub mod report;
+pub mod schema;
+mod secrets;
 pub mod settings;
+pub mod source;
@@ -0,0 +1,54 @@
+use chrono::{offset::Utc, DateTime};
+use overwatch::arcade_watcher::Target;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+/// What a run would have delivered, rendered the way it would be sent
+#[derive(Default)]
+pub struct Preview {
+    messages: BTreeMap<Target, Vec<String>>,
+    skipped: Vec<Target>,
+    held: BTreeSet<(Target, DateTime<Utc>)>,
+}
+
+impl Preview {
+    pub fn message(&mut self, target: &Target, rendered: String) {
+        self.messages
+            .entry(target.clone())
+            .or_default()
+            .push(rendered)
+    }
+    /// The target is disabled, so nothing would be sent to it
+    pub fn skipped(&mut self, target: &Target) {
+        self.skipped.push(target.clone())
+    }
+    /// It is quiet hours for the target, so a notification would wait
+    pub fn held(&mut self, target: &Target, until: DateTime<Utc>) {
+        self.held.insert((target.clone(), until));
+    }
+}
+
+impl fmt::Display for Preview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.messages.is_empty() && self.skipped.is_empty() && self.held.is_empty() {
+            return write!(f, "nothing would be delivered");
+        }
+        for (target, messages) in &self.messages {
+            writeln!(f, "{}", target)?;
+            for msg in messages {
+                for line in msg.lines() {
+                    writeln!(f, "  {}", line)?;
+                }
+            }
+        }
+        for target in &self.skipped {
+            writeln!(f, "{} is disabled, nothing would be sent", target)?;
+        }
+        for (target, until) in &self.held {
+            writeln!(f, "{} is in quiet hours, holding until {}", target, until)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use overwatch::{
+    i18n::{Text, Translator},
+    owatapi::OWTODAY_URL,
+    GameMode,
+};
+use pushnotify::{Message, PushServer};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+
+/// A push notification target and the gamemodes it cares about
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PushSubscription {
+    #[serde(flatten)]
+    pub server: PushServer,
+    #[serde(default)]
+    pub priority: Option<u8>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub locale: Option<String>,
+    /// Subscription groups whose gamemodes this wants as well as its own
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub gamemodes: Vec<GameMode>,
+    /// Fields we don't understand, kept so they survive being pulled and pushed
+    #[serde(flatten, deserialize_with = "without_server_fields")]
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+/// The flattened `server` doesn't consume its fields, so they would show up
+/// here as well
+fn without_server_fields<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, serde_json::Value>, D::Error> {
+    let mut extra = BTreeMap::<String, serde_json::Value>::deserialize(deserializer)?;
+    for field in PushServer::FIELDS {
+        extra.remove(*field);
+    }
+    Ok(extra)
+}
+
+/// The title and body of the notification for a gamemode
+pub fn render(gm: &GameMode, tr: &Translator) -> (String, String) {
+    let gm = tr.gamemode(gm);
+    let players = format!("{}: {}", tr.text(Text::Players), gm.players);
+    let body = match gm.description {
+        Some(ref desc) => format!("{}\n{}", players, desc),
+        None => players,
+    };
+    (gm.name, body)
+}
+
+pub fn send_gamemode(
+    sub: &PushSubscription,
+    gm: &GameMode,
+    tr: &Translator,
+) -> Result<(), failure::Error> {
+    let (title, body) = render(gm, tr);
+    sub.server.send(&Message {
+        title: &title,
+        body: &body,
+        priority: sub.priority,
+        tags: &sub.tags,
+        click: Some(OWTODAY_URL),
+        image: gm.image.as_ref().map(|img| img.url.as_str()),
+    })
+}
@@ -0,0 +1,73 @@
+use overwatch::{arcade_watcher::Target, GameMode};
+use std::{collections::BTreeMap, fmt};
+
+enum Outcome {
+    Delivered,
+    Failed(String),
+    Skipped,
+}
+
+/// What happened to each notification during a run
+#[derive(Default)]
+pub struct RunReport {
+    outcomes: BTreeMap<Target, Vec<(GameMode, Outcome)>>,
+    disabled: Vec<Target>,
+}
+
+impl RunReport {
+    pub fn delivered(&mut self, target: &Target, gm: &GameMode) {
+        self.record(target, gm, Outcome::Delivered)
+    }
+    pub fn failed(&mut self, target: &Target, gm: &GameMode, error: &failure::Error) {
+        self.record(target, gm, Outcome::Failed(error.to_string()))
+    }
+    /// The target has been disabled, so we didn't try, and the notification
+    /// is dropped
+    pub fn skipped(&mut self, target: &Target, gm: &GameMode) {
+        self.record(target, gm, Outcome::Skipped)
+    }
+    pub fn disabled(&mut self, target: &Target) {
+        self.disabled.push(target.clone())
+    }
+
+    pub fn failures(&self) -> usize {
+        self.outcomes
+            .values()
+            .flatten()
+            .filter(|(_, o)| matches!(o, Outcome::Failed(_)))
+            .count()
+    }
+
+    fn record(&mut self, target: &Target, gm: &GameMode, outcome: Outcome) {
+        self.outcomes
+            .entry(target.clone())
+            .or_default()
+            .push((gm.clone(), outcome))
+    }
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.outcomes.is_empty() {
+            return write!(f, "nothing to deliver");
+        }
+        for (target, outcomes) in &self.outcomes {
+            writeln!(f, "{}", target)?;
+            for (gm, outcome) in outcomes {
+                match outcome {
+                    Outcome::Delivered => writeln!(f, "  delivered: {}", gm.name)?,
+                    Outcome::Failed(e) => writeln!(f, "  failed: {}: {}", gm.name, e)?,
+                    Outcome::Skipped => writeln!(f, "  dropped (target disabled): {}", gm.name)?,
+                }
+            }
+        }
+        for target in &self.disabled {
+            writeln!(
+                f,
+                "disabled {} after repeated permanent failures, re-enable it with `state enable {}`",
+                target, target
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use crate::DynamicConfig;
+use failure::Fail;
+use schemars::{schema::RootSchema, schema_for};
+
+/// Where a config stopped making sense, rather than just serde's message
+#[derive(Debug, Fail, PartialEq)]
+#[fail(display = "line {}, column {}, at {}: {}", line, column, path, message)]
+pub struct ConfigParseError {
+    pub line: usize,
+    pub column: usize,
+    /// Like `watcher.rooms.1234.gamemodes[0]`
+    pub path: String,
+    pub message: String,
+}
+
+/// A JSON Schema for the config, for editors to check and complete it with
+pub fn config_schema() -> RootSchema {
+    schema_for!(DynamicConfig)
+}
+
+/// Parse a config, saying where in the document anything went wrong
+pub fn parse_config(text: &str) -> Result<DynamicConfig, ConfigParseError> {
+    let mut de = serde_json::Deserializer::from_str(text);
+    serde_path_to_error::deserialize(&mut de).map_err(|e| {
+        let path = e.path().to_string();
+        let e = e.into_inner();
+        let (line, column) = (e.line(), e.column());
+        // serde_json tacks the position on the end, we show it up front
+        let message = e.to_string();
+        let message = message
+            .trim_end_matches(&format!(" at line {} column {}", line, column))
+            .to_string();
+        ConfigParseError {
+            line,
+            column,
+            path,
+            message,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{config_schema, parse_config};
+
+    #[test]
+    fn errors_have_a_path() {
+        let text = r#"{
+  "watcher": {
+    "rooms": {
+      "1234": {
+        "gamemodes": [{"name": "Total Mayhem"}]
+      }
+    }
+  }
+}"#;
+        let e = parse_config(text).unwrap_err();
+        assert_eq!(e.path, "watcher.rooms.1234.gamemodes[0]");
+        assert_eq!(e.line, 5);
+        assert_eq!(e.message, "missing field `players`");
+
+        let schema = serde_json::to_value(config_schema()).unwrap();
+        assert!(schema["definitions"]["RoomConfig"]["properties"]["gamemodes"].is_object());
+    }
+}
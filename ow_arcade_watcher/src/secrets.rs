@@ -0,0 +1,19 @@
+use rusoto_core::Region;
+use rusoto_kms::{DecryptRequest, Kms, KmsClient};
+
+/// Decrypt a secret made with `aws kms encrypt`
+///
+/// The ciphertext records which KMS key it was encrypted with, so all we need
+/// is permission to use that key.
+pub fn kms_decrypt(region: Region, ciphertext: Vec<u8>) -> Result<String, failure::Error> {
+    let resp = KmsClient::new(region)
+        .decrypt(DecryptRequest {
+            ciphertext_blob: ciphertext.into(),
+            ..Default::default()
+        })
+        .sync()?;
+    let plaintext = resp
+        .plaintext
+        .ok_or_else(|| failure::err_msg("KMS did not return the decrypted secret"))?;
+    Ok(String::from_utf8(plaintext.to_vec())?.trim().to_string())
+}
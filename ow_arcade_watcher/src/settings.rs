@@ -1,32 +1,351 @@
+use crate::{secrets::kms_decrypt, source::ConfigSource};
 use config::Config;
+use failure::{bail, Fail};
+use overwatch::arcade_state::DEFAULT_HISTORY;
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+use stupids3::{LocalStore, MemoryStore, ObjectStore, StupidS3};
 
 const ENVIRONMENT_PREFIX: &str = "OWARCADEBOT";
+/// Names a settings file to load when none is given on the command line
+const SETTINGS_FILE_VAR: &str = "OWARCADEBOT_SETTINGS";
+
+/// Every setting we know about, in the order `config show-effective` lists them
+const KNOWN_SETTINGS: &[&str] = &[
+    "DISCORD_TOKEN",
+    "DISCORD_TOKEN_FILE",
+    "DISCORD_TOKEN_STORE_KEY",
+    "STORE",
+    "STORE_DIR",
+    "S3_BUCKET",
+    "S3_REGION",
+    "S3_ENDPOINT",
+    "S3_KEY_CONFIG",
+    "S3_KEY_GAMESTATE",
+    "S3_KEY_OUTBOX",
+    "CONFIG_SOURCE",
+    "STATE_HISTORY",
+];
+
+/// Where a setting came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    File(PathBuf),
+    Environment,
+    CommandLine,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::File(path) => write!(f, "file {}", path.display()),
+            Origin::Environment => write!(f, "environment"),
+            Origin::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// A setting as the program sees it, after every layer has been merged
+pub struct EffectiveSetting {
+    pub key: &'static str,
+    /// Secrets are already redacted
+    pub value: Option<String>,
+    pub origin: Option<Origin>,
+}
+
+impl fmt::Display for EffectiveSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.value, &self.origin) {
+            (Some(value), Some(origin)) => write!(f, "{} = {}  ({})", self.key, value, origin),
+            _ => write!(f, "{} is not set", self.key),
+        }
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    key.ends_with("_TOKEN") || key.ends_with("_SECRET") || key.ends_with("_PASSWORD")
+}
 
-#[derive(Debug)]
 pub struct ArcadeBotConfig {
-    inner: Config,
+    /// Highest priority first
+    layers: Vec<(Origin, Config)>,
+}
+
+/// Only shows the settings we know about, so secrets can be redacted
+impl fmt::Debug for ArcadeBotConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let effective = match self.effective() {
+            Ok(effective) => effective,
+            Err(e) => return write!(f, "ArcadeBotConfig {{ <{}> }}", e),
+        };
+        f.debug_map()
+            .entries(
+                effective
+                    .iter()
+                    .filter_map(|s| Some((s.key, s.value.as_ref()?))),
+            )
+            .finish()
+    }
 }
 
 impl ArcadeBotConfig {
     pub fn discord_token(&self) -> Result<String, failure::Error> {
-        Ok(self.inner.get_str("DISCORD_TOKEN")?)
+        self.secret("DISCORD_TOKEN")
     }
     pub fn s3_bucket(&self) -> Result<String, failure::Error> {
-        Ok(self.inner.get_str("S3_BUCKET")?)
+        self.get_str("S3_BUCKET")
     }
     pub fn s3_key_config(&self) -> Result<String, failure::Error> {
-        Ok(self.inner.get_str("S3_KEY_CONFIG")?)
+        self.get_str("S3_KEY_CONFIG")
+    }
+    /// Where the watcher config comes from, defaults to `S3_KEY_CONFIG` in the store
+    pub fn config_source(&self) -> Result<ConfigSource, failure::Error> {
+        match self.optional_str("CONFIG_SOURCE")? {
+            Some(source) => source.parse(),
+            None => Ok(ConfigSource::Store(self.s3_key_config()?)),
+        }
     }
     pub fn s3_key_gamestate(&self) -> Result<String, failure::Error> {
-        Ok(self.inner.get_str("S3_KEY_GAMESTATE")?)
+        self.get_str("S3_KEY_GAMESTATE")
+    }
+    /// Where undelivered notifications are kept, defaults to next to the gamestate
+    pub fn s3_key_outbox(&self) -> Result<String, failure::Error> {
+        match self.optional_str("S3_KEY_OUTBOX")? {
+            Some(key) => Ok(key),
+            None => Ok(format!("{}.outbox", self.s3_key_gamestate()?)),
+        }
+    }
+    /// Defaults to us-west-2
+    pub fn s3_region(&self) -> Result<Option<String>, failure::Error> {
+        self.optional_str("S3_REGION")
+    }
+    /// Talk to something other than AWS, like MinIO or LocalStack
+    pub fn s3_endpoint(&self) -> Result<Option<String>, failure::Error> {
+        self.optional_str("S3_ENDPOINT")
+    }
+    /// How many old versions of the state to keep around for restoring
+    pub fn state_history(&self) -> Result<usize, failure::Error> {
+        match self.optional_str("STATE_HISTORY")? {
+            Some(n) => Ok(n.parse()?),
+            None => Ok(DEFAULT_HISTORY),
+        }
+    }
+    pub fn s3(&self) -> Result<StupidS3, failure::Error> {
+        let region =
+            stupids3::region(self.s3_region()?.as_deref(), self.s3_endpoint()?.as_deref())?;
+        Ok(StupidS3::new(region, self.s3_bucket()?))
+    }
+    /// Where the config and state live: `s3` (the default), `local` or `memory`
+    ///
+    /// `local` keeps everything under `STORE_DIR`, using the same key names.
+    pub fn store(&self) -> Result<Box<dyn ObjectStore>, failure::Error> {
+        Ok(
+            match self.optional_str("STORE")?.as_deref().unwrap_or("s3") {
+                "s3" => Box::new(self.s3()?),
+                "local" => Box::new(LocalStore::new(self.get_str("STORE_DIR")?)),
+                "memory" => Box::new(MemoryStore::default()),
+                other => bail!("unknown store {:?}, expected s3, local or memory", other),
+            },
+        )
+    }
+
+    /// Every known setting, with where its value came from
+    pub fn effective(&self) -> Result<Vec<EffectiveSetting>, failure::Error> {
+        KNOWN_SETTINGS
+            .iter()
+            .map(|&key| {
+                let found = self.lookup(key)?;
+                Ok(EffectiveSetting {
+                    key,
+                    value: found.as_ref().map(|(value, _)| {
+                        if is_secret(key) {
+                            "<redacted>".to_string()
+                        } else {
+                            value.clone()
+                        }
+                    }),
+                    origin: found.map(|(_, origin)| origin.clone()),
+                })
+            })
+            .collect()
+    }
+
+    /// A secret, given directly or found through `{key}_FILE` or `{key}_STORE_KEY`
+    ///
+    /// `_FILE` names a file holding the secret, as Docker and Kubernetes
+    /// secrets are mounted. `_STORE_KEY` names an object in the store holding
+    /// the secret encrypted with `aws kms encrypt`. The highest priority layer
+    /// with any of the three wins, and within a layer they are tried in that
+    /// order.
+    fn secret(&self, key: &str) -> Result<String, failure::Error> {
+        let file_key = format!("{}_FILE", key);
+        let store_key = format!("{}_STORE_KEY", key);
+        for (_, layer) in &self.layers {
+            if let Some(value) = layer_str(layer, key)? {
+                return Ok(value);
+            }
+            if let Some(path) = layer_str(layer, &file_key)? {
+                let value = fs::read_to_string(&path)
+                    .map_err(|e| e.context(format!("reading {} from {}", key, path)))?;
+                return Ok(value.trim().to_string());
+            }
+            if let Some(object) = layer_str(layer, &store_key)? {
+                let ciphertext = self.store()?.get_bytes(&object)?.value;
+                let region = stupids3::region(self.s3_region()?.as_deref(), None)?;
+                return kms_decrypt(region, ciphertext).map_err(|e| {
+                    e.context(format!("decrypting {} from {}", key, object))
+                        .into()
+                });
+            }
+        }
+        Err(config::ConfigError::NotFound(key.to_string()).into())
+    }
+
+    /// The value of a setting from the highest priority layer that has it
+    fn lookup(&self, key: &str) -> Result<Option<(String, &Origin)>, failure::Error> {
+        for (origin, layer) in &self.layers {
+            if let Some(value) = layer_str(layer, key)? {
+                return Ok(Some((value, origin)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_str(&self, key: &str) -> Result<String, failure::Error> {
+        match self.optional_str(key)? {
+            Some(value) => Ok(value),
+            None => Err(config::ConfigError::NotFound(key.to_string()).into()),
+        }
+    }
+
+    fn optional_str(&self, key: &str) -> Result<Option<String>, failure::Error> {
+        Ok(self.lookup(key)?.map(|(value, _)| value))
+    }
+}
+
+fn layer_str(layer: &Config, key: &str) -> Result<Option<String>, failure::Error> {
+    match layer.get_str(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e.into()),
     }
 }
 
+/// Load settings from the environment, and the file named by `OWARCADEBOT_SETTINGS`
 pub fn load() -> Result<ArcadeBotConfig, failure::Error> {
-    let mut c = config::Config::default();
+    load_from(None, &[])
+}
 
-    let config_env = config::Environment::with_prefix(ENVIRONMENT_PREFIX).separator("__");
-    c.merge(config_env)?;
+/// Load settings from a TOML/YAML/JSON file, then the environment, then `overrides`
+///
+/// Later layers win. Without a `file`, the one named by `OWARCADEBOT_SETTINGS`
+/// is used if that is set.
+pub fn load_from(
+    file: Option<&Path>,
+    overrides: &[(String, String)],
+) -> Result<ArcadeBotConfig, failure::Error> {
+    load_with_env(file, overrides, env::vars())
+}
+
+/// The `OWARCADEBOT_` variables, with `__` separating nested keys
+fn environment_layer(vars: &[(String, String)]) -> Result<Config, failure::Error> {
+    let prefix = format!("{}_", ENVIRONMENT_PREFIX);
+    let mut layer = Config::default();
+    for (key, value) in vars {
+        if key.len() > prefix.len() && key[..prefix.len()].eq_ignore_ascii_case(&prefix) {
+            let key = key[prefix.len()..].replace("__", ".").to_lowercase();
+            layer.set(&key, value.as_str())?;
+        }
+    }
+    Ok(layer)
+}
 
-    Ok(ArcadeBotConfig { inner: c })
+/// Like `load_from`, with the environment given rather than read from the process
+fn load_with_env<I>(
+    file: Option<&Path>,
+    overrides: &[(String, String)],
+    vars: I,
+) -> Result<ArcadeBotConfig, failure::Error>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let vars: Vec<_> = vars.into_iter().collect();
+    let mut layers = Vec::new();
+
+    let mut cli = Config::default();
+    for (key, value) in overrides {
+        cli.set(&key.to_uppercase(), value.as_str())?;
+    }
+    layers.push((Origin::CommandLine, cli));
+
+    layers.push((Origin::Environment, environment_layer(&vars)?));
+
+    let file = file.map(PathBuf::from).or_else(|| {
+        vars.iter()
+            .find(|(key, _)| key == SETTINGS_FILE_VAR)
+            .map(|(_, path)| PathBuf::from(path))
+    });
+    if let Some(path) = file {
+        let mut layer = Config::default();
+        layer.merge(config::File::from(path.as_path()))?;
+        layers.push((Origin::File(path), layer));
+    }
+
+    Ok(ArcadeBotConfig { layers })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load_with_env, Origin};
+    use std::{env, fs, process};
+
+    #[test]
+    fn overrides_win_and_secrets_are_redacted() {
+        let cfg = load_with_env(
+            None,
+            &[
+                ("s3_bucket".to_string(), "from-cli".to_string()),
+                ("DISCORD_TOKEN".to_string(), "hunter2".to_string()),
+            ],
+            vec![("OWARCADEBOT_S3_BUCKET".to_string(), "from-env".to_string())],
+        )
+        .unwrap();
+        assert_eq!(cfg.s3_bucket().unwrap(), "from-cli");
+        assert_eq!(cfg.discord_token().unwrap(), "hunter2");
+
+        let effective = cfg.effective().unwrap();
+        let token = effective.iter().find(|s| s.key == "DISCORD_TOKEN").unwrap();
+        assert_eq!(token.value.as_deref(), Some("<redacted>"));
+        assert_eq!(token.origin, Some(Origin::CommandLine));
+        assert!(!effective.iter().any(|s| s.to_string().contains("hunter2")));
+    }
+
+    #[test]
+    fn token_from_file_is_not_logged() {
+        let path = env::temp_dir().join(format!("owarcadebot-token-{}", process::id()));
+        fs::write(&path, "hunter2\n").unwrap();
+        // the file is given on the command line, so it beats the environment
+        let environment = vec![(
+            "OWARCADEBOT_DISCORD_TOKEN".to_string(),
+            "from-env".to_string(),
+        )];
+        let cfg = load_with_env(
+            None,
+            &[("DISCORD_TOKEN_FILE".to_string(), path.display().to_string())],
+            environment.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            load_with_env(None, &[], environment)
+                .unwrap()
+                .discord_token()
+                .unwrap(),
+            "from-env"
+        );
+        assert_eq!(cfg.discord_token().unwrap(), "hunter2");
+        assert!(!format!("{:#?}", cfg).contains("hunter2"));
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,91 @@
+use crate::{schema::parse_config, DynamicConfig};
+use std::{fmt, fs, path::PathBuf, str::FromStr};
+use stupids3::ObjectStore;
+
+/// Where to load the `DynamicConfig` from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigSource {
+    /// A key in the configured store, written as `store:KEY`
+    Store(String),
+    /// An `http://` or `https://` URL
+    Url(String),
+    /// Anything else is a path to a local file
+    File(PathBuf),
+}
+
+impl ConfigSource {
+    /// Every source goes through `parse_config`, so errors say where they are
+    pub fn load(&self, store: &dyn ObjectStore) -> Result<DynamicConfig, failure::Error> {
+        let text = match self {
+            ConfigSource::Store(key) => store.get(key)?,
+            ConfigSource::Url(url) => reqwest::get(url)?.error_for_status()?.text()?,
+            ConfigSource::File(path) => fs::read_to_string(path)?,
+        };
+        Ok(parse_config(&text)?)
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Store(key) => write!(f, "store:{}", key),
+            ConfigSource::Url(url) => write!(f, "{}", url),
+            ConfigSource::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for ConfigSource {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Result<ConfigSource, failure::Error> {
+        if let Some(key) = s.strip_prefix("store:") {
+            Ok(ConfigSource::Store(key.to_string()))
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(ConfigSource::Url(s.to_string()))
+        } else if s.is_empty() {
+            failure::bail!("the config source can't be empty")
+        } else {
+            Ok(ConfigSource::File(s.into()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConfigSource;
+    use crate::schema::ConfigParseError;
+    use stupids3::{Expect, MemoryStore, ObjectStore};
+
+    #[test]
+    fn parse_source() {
+        for (s, source) in &[
+            ("store:cfg.json", ConfigSource::Store("cfg.json".into())),
+            (
+                "https://example.com/cfg.json",
+                ConfigSource::Url("https://example.com/cfg.json".into()),
+            ),
+            ("./cfg.json", ConfigSource::File("./cfg.json".into())),
+        ] {
+            assert_eq!(&s.parse::<ConfigSource>().unwrap(), source);
+            assert_eq!(source.to_string(), *s);
+        }
+        assert!("".parse::<ConfigSource>().is_err());
+    }
+
+    #[test]
+    fn store_errors_have_a_path() {
+        let store = MemoryStore::default();
+        store
+            .put_bytes(
+                "cfg.json",
+                br#"{"watcher": {"rooms": 1}}"#.to_vec(),
+                Expect::Anything,
+            )
+            .unwrap();
+        let e = ConfigSource::Store("cfg.json".into())
+            .load(&store)
+            .unwrap_err();
+        let e = e.downcast::<ConfigParseError>().unwrap();
+        assert_eq!(e.path, "watcher.rooms");
+    }
+}
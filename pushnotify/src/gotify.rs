@@ -0,0 +1,53 @@
+use crate::Message;
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Serialize)]
+struct Publish<'a> {
+    title: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<u8>,
+    extras: serde_json::Value,
+}
+
+/// Publish a message to a gotify application
+///
+/// Gotify has no concept of tags, so they are appended to the message body
+/// as hashtags.
+pub fn send(url: &str, token: &str, msg: &Message) -> Result<(), failure::Error> {
+    let mut message = msg.body.to_string();
+    if !msg.tags.is_empty() {
+        let tags = msg
+            .tags
+            .iter()
+            .map(|t| format!("#{}", t))
+            .collect::<Vec<_>>()
+            .join(" ");
+        message.push_str("\n\n");
+        message.push_str(&tags);
+    }
+    let mut extras = json!({
+        "client::display": {"contentType": "text/markdown"},
+    });
+    if let Some(click) = msg.click {
+        extras["client::notification"] = json!({"click": {"url": click}});
+    }
+    if let Some(image) = msg.image {
+        extras["client::notification"]["bigImageUrl"] = json!(image);
+    }
+    let body = Publish {
+        title: msg.title,
+        message,
+        priority: msg.priority,
+        extras,
+    };
+    debug!("gotify publish to {}", url);
+    reqwest::Client::new()
+        .post(&format!("{}/message", url.trim_end_matches('/')))
+        .header("X-Gotify-Key", token)
+        .json(&body)
+        .send()?
+        .error_for_status()?;
+    Ok(())
+}
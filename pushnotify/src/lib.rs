@@ -0,0 +1,102 @@
+#[macro_use]
+extern crate log;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub mod gotify;
+pub mod ntfy;
+
+/// A self-hosted push notification server
+///
+/// `Debug` leaves out the token, so a config can be printed safely.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "service", rename_all = "lowercase")]
+pub enum PushServer {
+    Ntfy {
+        url: String,
+        topic: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    Gotify {
+        url: String,
+        token: String,
+    },
+}
+
+impl fmt::Debug for PushServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const REDACTED: &str = "<redacted>";
+        match self {
+            PushServer::Ntfy { url, topic, token } => f
+                .debug_struct("Ntfy")
+                .field("url", url)
+                .field("topic", topic)
+                .field("token", &token.as_ref().map(|_| REDACTED))
+                .finish(),
+            PushServer::Gotify { url, .. } => f
+                .debug_struct("Gotify")
+                .field("url", url)
+                .field("token", &REDACTED)
+                .finish(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Message<'a> {
+    pub title: &'a str,
+    pub body: &'a str,
+    pub priority: Option<u8>,
+    pub tags: &'a [String],
+    pub click: Option<&'a str>,
+    pub image: Option<&'a str>,
+}
+
+/// Whether retrying won't help, e.g. a bad token or a deleted topic
+pub fn is_permanent_error(err: &failure::Error) -> bool {
+    let status = err
+        .downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .map(|s| s.as_u16());
+    matches!(status, Some(401) | Some(403) | Some(404))
+}
+
+impl PushServer {
+    /// Every field a server can be configured with, for any service
+    pub const FIELDS: &'static [&'static str] = &["service", "url", "topic", "token"];
+
+    pub fn send(&self, msg: &Message) -> Result<(), failure::Error> {
+        match self {
+            PushServer::Ntfy { url, topic, token } => ntfy::send(url, topic, token.as_deref(), msg),
+            PushServer::Gotify { url, token } => gotify::send(url, token, msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PushServer;
+
+    #[test]
+    fn debug_hides_tokens() {
+        let servers = [
+            PushServer::Ntfy {
+                url: "https://ntfy.sh".into(),
+                topic: "arcade".into(),
+                token: Some("hunter2".into()),
+            },
+            PushServer::Gotify {
+                url: "https://gotify.example.com".into(),
+                token: "hunter2".into(),
+            },
+        ];
+        for server in &servers {
+            let debug = format!("{:#?}", server);
+            assert!(debug.contains("<redacted>"), "{}", debug);
+            assert!(!debug.contains("hunter2"), "{}", debug);
+        }
+    }
+}
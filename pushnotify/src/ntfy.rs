@@ -0,0 +1,46 @@
+use crate::Message;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Publish<'a> {
+    topic: &'a str,
+    title: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<u8>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    tags: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    click: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attach: Option<&'a str>,
+}
+
+/// Publish a message to an ntfy topic
+///
+/// ntfy priorities run from 1 (min) to 5 (max)
+pub fn send(
+    url: &str,
+    topic: &str,
+    token: Option<&str>,
+    msg: &Message,
+) -> Result<(), failure::Error> {
+    let body = Publish {
+        topic,
+        title: msg.title,
+        message: msg.body,
+        priority: msg.priority.map(|p| p.clamp(1, 5)),
+        tags: msg.tags,
+        click: msg.click,
+        attach: msg.image,
+    };
+    debug!("ntfy publish to {}/{}", url, topic);
+    let mut req = reqwest::Client::new()
+        .post(url.trim_end_matches('/'))
+        .json(&body);
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+    req.send()?.error_for_status()?;
+    Ok(())
+}
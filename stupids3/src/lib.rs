@@ -1,19 +1,48 @@
 use failure::Fail;
-use rusoto_core::{ByteStream, Region, RusotoError};
-use rusoto_s3::{GetObjectError, GetObjectRequest, PutObjectRequest, S3Client, S3};
 use serde::{de::DeserializeOwned, Serialize};
-use std::io::Read;
+use std::sync::Arc;
+
+pub mod local;
+pub mod memory;
+mod s3;
+
+pub use crate::{
+    local::LocalStore,
+    memory::MemoryStore,
+    s3::{region, StupidS3},
+};
 
 #[derive(Debug, Fail)]
 pub enum StupidS3Error {
     #[fail(display = "The key {}/{} does not exist", bucket, key)]
     NoSuchKey { bucket: String, key: String },
+    #[fail(display = "The key {}/{} was changed by someone else", bucket, key)]
+    PreconditionFailed { bucket: String, key: String },
+    #[fail(display = "Access to {}/{} was denied", bucket, key)]
+    AccessDenied {
+        bucket: String,
+        key: String,
+        #[fail(cause)]
+        error: failure::Error,
+    },
+    #[fail(
+        display = "The store was unavailable while accessing {}/{}",
+        bucket, key
+    )]
+    Unavailable {
+        bucket: String,
+        key: String,
+        #[fail(cause)]
+        error: failure::Error,
+    },
     #[fail(display = "The content was missing")]
     ContentMissing,
     #[fail(display = "The content was invalid")]
     ContentInvalid(#[fail(cause)] failure::Error),
+    #[fail(display = "The object could not be serialized")]
+    Serialize(#[fail(cause)] failure::Error),
     #[fail(
-        display = "An unknown error occurred while retrieving {}/{} from S3",
+        display = "An unknown error occurred while accessing {}/{}",
         bucket, key
     )]
     UnknownError {
@@ -24,65 +53,195 @@ pub enum StupidS3Error {
     },
 }
 
-fn get_raw<B: AsRef<str>, K: AsRef<str>>(bucket: B, key: K) -> Result<ByteStream, StupidS3Error> {
-    let s3_client = S3Client::new(Region::UsWest2);
-    let resp = s3_client
-        .get_object(GetObjectRequest {
-            bucket: bucket.as_ref().into(),
-            key: key.as_ref().into(),
-            ..Default::default()
-        })
-        .sync()
-        .map_err(|e| match e {
-            RusotoError::Service(service_err) => match service_err {
-                GetObjectError::NoSuchKey(_) => StupidS3Error::NoSuchKey {
-                    bucket: bucket.as_ref().into(),
-                    key: key.as_ref().into(),
-                },
-            },
-            _ => StupidS3Error::UnknownError {
-                bucket: bucket.as_ref().into(),
-                key: key.as_ref().into(),
-                error: e.into(),
-            },
-        })?;
-    let bytes = resp.body.ok_or(StupidS3Error::ContentMissing)?;
-    Ok(bytes)
+impl StupidS3Error {
+    /// Whether trying the same thing again later might work
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, StupidS3Error::Unavailable { .. })
+    }
+
+    /// Whether the stored object exists but can't be read back
+    pub fn is_corrupt(&self) -> bool {
+        matches!(
+            self,
+            StupidS3Error::ContentMissing | StupidS3Error::ContentInvalid(_)
+        )
+    }
+}
+
+/// An object along with the ETag it had when we read it
+#[derive(Debug, Clone)]
+pub struct Versioned<S> {
+    pub value: S,
+    pub etag: String,
+}
+
+/// What a write expects to find in the store
+///
+/// Every store checks this as part of the write, so of two writers expecting
+/// the same thing, only one succeeds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expect<'a> {
+    /// Write no matter what is there
+    Anything,
+    /// The key must not exist yet
+    Missing,
+    /// The key must still have this ETag
+    ETag(&'a str),
 }
 
-pub fn get<B: AsRef<str>, K: AsRef<str>>(bucket: B, key: K) -> Result<String, StupidS3Error> {
-    let bytes = get_raw(bucket, key)?;
-    let mut buf = String::new();
-    bytes
-        .into_blocking_read()
-        .read_to_string(&mut buf)
-        .map_err(|e| StupidS3Error::ContentInvalid(e.into()))?;
-    Ok(buf)
+impl<'a> Expect<'a> {
+    /// Check the ETag currently in the store against what we expected
+    fn check(self, bucket: &str, key: &str, current: Option<&str>) -> Result<(), StupidS3Error> {
+        let ok = match (self, current) {
+            (Expect::Anything, _) => true,
+            (Expect::Missing, current) => current.is_none(),
+            (Expect::ETag(etag), Some(current)) => etag == current,
+            (Expect::ETag(_), None) => false,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(StupidS3Error::PreconditionFailed {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+            })
+        }
+    }
 }
 
-pub fn get_obj<S: DeserializeOwned, B: AsRef<str>, K: AsRef<str>>(
-    bucket: B,
-    key: K,
-) -> Result<S, StupidS3Error> {
-    let bytes = get_raw(bucket, key)?;
-    let obj: S = serde_json::from_reader(bytes.into_blocking_read())
-        .map_err(|e| StupidS3Error::ContentInvalid(e.into()))?;
-    Ok(obj)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub etag: Option<String>,
 }
 
-pub fn put<S: Serialize, B: AsRef<str>, K: AsRef<str>>(
-    bucket: B,
-    key: K,
-    obj: &S,
-) -> Result<(), failure::Error> {
-    let s3_client = S3Client::new(Region::UsWest2);
-    let _resp = s3_client
-        .put_object(PutObjectRequest {
-            bucket: bucket.as_ref().into(),
-            key: key.as_ref().into(),
-            body: Some(serde_json::to_string_pretty(obj)?.into_bytes().into()),
-            ..Default::default()
-        })
-        .sync()?;
-    Ok(())
+/// Somewhere to keep named blobs, like an S3 bucket
+pub trait ObjectStore {
+    fn get_bytes(&self, key: &str) -> Result<Versioned<Vec<u8>>, StupidS3Error>;
+    /// Write an object, returning its new ETag
+    fn put_bytes(&self, key: &str, body: Vec<u8>, expect: Expect) -> Result<String, StupidS3Error>;
+    /// Every object whose key starts with `prefix`, sorted by key
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StupidS3Error>;
+    /// Deleting something that doesn't exist is not an error
+    fn delete(&self, key: &str) -> Result<(), StupidS3Error>;
+}
+
+/// So several users can share one store
+impl<T: ObjectStore + ?Sized> ObjectStore for Arc<T> {
+    fn get_bytes(&self, key: &str) -> Result<Versioned<Vec<u8>>, StupidS3Error> {
+        (**self).get_bytes(key)
+    }
+    fn put_bytes(&self, key: &str, body: Vec<u8>, expect: Expect) -> Result<String, StupidS3Error> {
+        (**self).put_bytes(key, body, expect)
+    }
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StupidS3Error> {
+        (**self).list(prefix)
+    }
+    fn delete(&self, key: &str) -> Result<(), StupidS3Error> {
+        (**self).delete(key)
+    }
+}
+
+impl<'a> dyn ObjectStore + 'a {
+    pub fn get(&self, key: &str) -> Result<String, StupidS3Error> {
+        let bytes = self.get_bytes(key)?.value;
+        String::from_utf8(bytes).map_err(|e| StupidS3Error::ContentInvalid(e.into()))
+    }
+
+    pub fn get_obj<S: DeserializeOwned>(&self, key: &str) -> Result<S, StupidS3Error> {
+        self.get_with_version(key).map(|v| v.value)
+    }
+
+    /// Like `get_obj`, but also returns the version to hand back to `put_if_match`
+    pub fn get_with_version<S: DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> Result<Versioned<S>, StupidS3Error> {
+        let Versioned { value, etag } = self.get_bytes(key)?;
+        let value: S =
+            serde_json::from_slice(&value).map_err(|e| StupidS3Error::ContentInvalid(e.into()))?;
+        Ok(Versioned { value, etag })
+    }
+
+    pub fn put<S: Serialize>(&self, key: &str, obj: &S) -> Result<String, StupidS3Error> {
+        self.put_if_match(key, obj, Expect::Anything)
+    }
+
+    /// Write an object, but only if the store still holds what we expect
+    pub fn put_if_match<S: Serialize>(
+        &self,
+        key: &str,
+        obj: &S,
+        expect: Expect,
+    ) -> Result<String, StupidS3Error> {
+        let body =
+            serde_json::to_string_pretty(obj).map_err(|e| StupidS3Error::Serialize(e.into()))?;
+        self.put_bytes(key, body.into_bytes(), expect)
+    }
+}
+
+/// A content hash for stores that don't have ETags of their own
+///
+/// ETags end up on disk, e.g. next to a pulled config, so this must not
+/// change between builds. It is the MD5, like S3 uses for simple uploads.
+fn content_etag(body: &[u8]) -> String {
+    format!("{:x}", md5::compute(body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Run the same checks against every kind of store
+    pub(crate) fn exercise_store(store: &dyn ObjectStore) {
+        let mut obj = HashMap::new();
+        obj.insert("a".to_string(), 1);
+
+        match store.get_obj::<HashMap<String, u32>>("dir/obj.json") {
+            Err(StupidS3Error::NoSuchKey { .. }) => {}
+            r => panic!("expected NoSuchKey, got {:?}", r),
+        }
+        let etag = store
+            .put_if_match("dir/obj.json", &obj, Expect::Missing)
+            .unwrap();
+        assert!(store
+            .put_if_match("dir/obj.json", &obj, Expect::Missing)
+            .is_err());
+
+        let read = store
+            .get_with_version::<HashMap<String, u32>>("dir/obj.json")
+            .unwrap();
+        assert_eq!(read.value, obj);
+        assert_eq!(read.etag, etag);
+
+        obj.insert("b".to_string(), 2);
+        let etag2 = store
+            .put_if_match("dir/obj.json", &obj, Expect::ETag(&etag))
+            .unwrap();
+        match store.put_if_match("dir/obj.json", &obj, Expect::ETag(&etag)) {
+            Err(StupidS3Error::PreconditionFailed { .. }) => {}
+            r => panic!("expected PreconditionFailed, got {:?}", r),
+        }
+        assert_ne!(etag, etag2);
+
+        store.put("dir/other.json", &obj).unwrap();
+        store.put("elsewhere.json", &obj).unwrap();
+        let keys = store
+            .list("dir/")
+            .unwrap()
+            .into_iter()
+            .map(|o| o.key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["dir/obj.json", "dir/other.json"]);
+
+        store.delete("dir/obj.json").unwrap();
+        store.delete("dir/obj.json").unwrap();
+        assert!(store.get("dir/obj.json").is_err());
+    }
+
+    #[test]
+    fn content_etags_are_stable() {
+        assert_eq!(content_etag(b"{}"), "99914b932bd37a50b983c5e7c90ae93b");
+    }
 }
@@ -0,0 +1,172 @@
+use crate::{content_etag, Expect, ObjectInfo, ObjectStore, StupidS3Error, Versioned};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const LOCK_SUFFIX: &str = ".lock";
+/// A lock this old was left behind by a writer that died
+const STALE_LOCK: Duration = Duration::from_secs(60);
+
+/// A store backed by a directory, where keys are paths relative to it
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> LocalStore {
+        LocalStore { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    fn io_error(&self, key: &str, e: io::Error) -> StupidS3Error {
+        let kind = e.kind();
+        let (bucket, key, error) = (self.root.display().to_string(), key.to_string(), e.into());
+        match kind {
+            io::ErrorKind::PermissionDenied => StupidS3Error::AccessDenied { bucket, key, error },
+            io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                StupidS3Error::Unavailable { bucket, key, error }
+            }
+            _ => StupidS3Error::UnknownError { bucket, key, error },
+        }
+    }
+
+    /// Hold `{key}.lock` while checking and writing, so a conditional write
+    /// can't race another one
+    fn lock(&self, key: &str) -> Result<Lock, StupidS3Error> {
+        let path = PathBuf::from(format!("{}{}", self.path(key).display(), LOCK_SUFFIX));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| self.io_error(key, e))?;
+        }
+        let stale = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .map(|t| t.elapsed().is_ok_and(|age| age > STALE_LOCK))
+            .unwrap_or(false);
+        if stale {
+            log::warn!("removing stale lock {}", path.display());
+            let _ = fs::remove_file(&path);
+        }
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(_) => Ok(Lock(path)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(StupidS3Error::PreconditionFailed {
+                    bucket: self.root.display().to_string(),
+                    key: key.to_string(),
+                })
+            }
+            Err(e) => Err(self.io_error(key, e)),
+        }
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, StupidS3Error> {
+        match fs::read(self.path(key)) {
+            Ok(body) => Ok(Some(body)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(self.io_error(key, e)),
+        }
+    }
+}
+
+/// Removes the lock file when dropped
+struct Lock(PathBuf);
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn walk(root: &Path, dir: &Path, keys: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(root, &path, keys)?;
+        } else if let Ok(rel) = path.strip_prefix(root) {
+            let key = rel
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            keys.push(key);
+        }
+    }
+    Ok(())
+}
+
+impl ObjectStore for LocalStore {
+    fn get_bytes(&self, key: &str) -> Result<Versioned<Vec<u8>>, StupidS3Error> {
+        let value = self.read(key)?.ok_or_else(|| StupidS3Error::NoSuchKey {
+            bucket: self.root.display().to_string(),
+            key: key.to_string(),
+        })?;
+        let etag = content_etag(&value);
+        Ok(Versioned { value, etag })
+    }
+
+    fn put_bytes(&self, key: &str, body: Vec<u8>, expect: Expect) -> Result<String, StupidS3Error> {
+        let _lock = self.lock(key)?;
+        let current = self.read(key)?.map(|v| content_etag(&v));
+        expect.check(&self.root.display().to_string(), key, current.as_deref())?;
+        let path = self.path(key);
+        let etag = content_etag(&body);
+        fs::write(&path, body).map_err(|e| self.io_error(key, e))?;
+        Ok(etag)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StupidS3Error> {
+        let mut keys = Vec::new();
+        if self.root.is_dir() {
+            walk(&self.root, &self.root, &mut keys).map_err(|e| self.io_error(prefix, e))?;
+        }
+        keys.retain(|k| k.starts_with(prefix) && !k.ends_with(LOCK_SUFFIX));
+        keys.sort();
+        keys.into_iter()
+            .map(|key| {
+                let etag = self.read(&key)?.map(|v| content_etag(&v));
+                Ok(ObjectInfo { key, etag })
+            })
+            .collect()
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StupidS3Error> {
+        match fs::remove_file(self.path(key)) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(self.io_error(key, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LocalStore;
+    use crate::{test::exercise_store, Expect, ObjectStore, StupidS3Error};
+    use std::{env, fs, process};
+
+    #[test]
+    fn local_store() {
+        let root = env::temp_dir().join(format!("stupids3-test-{}", process::id()));
+        let store = LocalStore::new(&root);
+        exercise_store(&store);
+
+        // a write in progress elsewhere holds the lock
+        let lock = store.lock("held.json").unwrap();
+        match store.put_bytes("held.json", b"{}".to_vec(), Expect::Anything) {
+            Err(StupidS3Error::PreconditionFailed { .. }) => {}
+            r => panic!("expected PreconditionFailed, got {:?}", r),
+        }
+        drop(lock);
+        store
+            .put_bytes("held.json", b"{}".to_vec(), Expect::Anything)
+            .unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
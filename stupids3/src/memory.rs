@@ -0,0 +1,62 @@
+use crate::{content_etag, Expect, ObjectInfo, ObjectStore, StupidS3Error, Versioned};
+use std::{collections::BTreeMap, sync::Mutex};
+
+const BUCKET: &str = "memory";
+
+/// A store that forgets everything when dropped, for tests and dry runs
+#[derive(Default)]
+pub struct MemoryStore {
+    objects: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl ObjectStore for MemoryStore {
+    fn get_bytes(&self, key: &str) -> Result<Versioned<Vec<u8>>, StupidS3Error> {
+        let objects = self.objects.lock().unwrap();
+        let value = objects
+            .get(key)
+            .cloned()
+            .ok_or_else(|| StupidS3Error::NoSuchKey {
+                bucket: BUCKET.to_string(),
+                key: key.to_string(),
+            })?;
+        let etag = content_etag(&value);
+        Ok(Versioned { value, etag })
+    }
+
+    fn put_bytes(&self, key: &str, body: Vec<u8>, expect: Expect) -> Result<String, StupidS3Error> {
+        let mut objects = self.objects.lock().unwrap();
+        let current = objects.get(key).map(|v| content_etag(v));
+        expect.check(BUCKET, key, current.as_deref())?;
+        let etag = content_etag(&body);
+        objects.insert(key.to_string(), body);
+        Ok(etag)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StupidS3Error> {
+        let objects = self.objects.lock().unwrap();
+        Ok(objects
+            .iter()
+            .filter(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| ObjectInfo {
+                key: k.clone(),
+                etag: Some(content_etag(v)),
+            })
+            .collect())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StupidS3Error> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MemoryStore;
+    use crate::test::exercise_store;
+
+    #[test]
+    fn memory_store() {
+        exercise_store(&MemoryStore::default());
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{Expect, ObjectInfo, ObjectStore, StupidS3Error, Versioned};
+use futures::{future, Future};
+use rusoto_core::{request::HttpResponse, signature::SignedRequest, Client, Region, RusotoError};
+use rusoto_s3::{
+    DeleteObjectRequest, GetObjectError, GetObjectRequest, ListObjectsV2Request, PutObjectError,
+    S3Client, S3,
+};
+use std::{error::Error, io::Read, str::FromStr, thread, time::Duration};
+
+const DEFAULT_REGION: &str = "us-west-2";
+const MAX_RETRIES: u32 = 3;
+const BASE_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Pick the region to talk to
+///
+/// With an `endpoint`, requests go there instead of AWS (e.g. MinIO or
+/// LocalStack), and `name` is only used for signing.
+pub fn region(name: Option<&str>, endpoint: Option<&str>) -> Result<Region, failure::Error> {
+    Ok(match (name, endpoint) {
+        (name, Some(endpoint)) => Region::Custom {
+            name: name.unwrap_or(DEFAULT_REGION).to_string(),
+            endpoint: endpoint.to_string(),
+        },
+        (Some(name), None) => Region::from_str(name)?,
+        (None, None) => Region::from_str(DEFAULT_REGION)?,
+    })
+}
+
+/// An S3 bucket, cheap to clone
+#[derive(Clone)]
+pub struct StupidS3 {
+    client: S3Client,
+    /// For requests `S3Client` can't make, like conditional writes
+    http: Client,
+    region: Region,
+    bucket: String,
+}
+
+impl StupidS3 {
+    pub fn new<B: Into<String>>(region: Region, bucket: B) -> StupidS3 {
+        StupidS3::with_client(Client::shared(), region, bucket)
+    }
+
+    fn with_client<B: Into<String>>(http: Client, region: Region, bucket: B) -> StupidS3 {
+        StupidS3 {
+            client: S3Client::new(region.clone()),
+            http,
+            region,
+            bucket: bucket.into(),
+        }
+    }
+
+    /// Sort a rusoto error into something callers can act on
+    fn classify<E>(&self, key: &str, e: RusotoError<E>) -> StupidS3Error
+    where
+        E: Error + Send + Sync + 'static,
+    {
+        let status = match e {
+            RusotoError::Unknown(ref resp) => Some(resp.status.as_u16()),
+            _ => None,
+        };
+        let unavailable = matches!(e, RusotoError::HttpDispatch(_))
+            || matches!(status, Some(429) | Some(500..=599));
+        let denied =
+            matches!(e, RusotoError::Credentials(_)) || matches!(status, Some(401) | Some(403));
+        // 409 is a conditional write racing another one on the same key
+        let changed = matches!(status, Some(409) | Some(412));
+        let (bucket, key) = (self.bucket.clone(), key.to_string());
+        if changed {
+            return StupidS3Error::PreconditionFailed { bucket, key };
+        }
+        let error = e.into();
+        if unavailable {
+            StupidS3Error::Unavailable { bucket, key, error }
+        } else if denied {
+            StupidS3Error::AccessDenied { bucket, key, error }
+        } else {
+            StupidS3Error::UnknownError { bucket, key, error }
+        }
+    }
+}
+
+/// The ETag a PUT responded with
+fn put_response(
+    response: HttpResponse,
+) -> Box<dyn Future<Item = Option<String>, Error = RusotoError<PutObjectError>> + Send> {
+    if !response.status.is_success() {
+        return Box::new(
+            response
+                .buffer()
+                .from_err()
+                .and_then(|response| future::err(PutObjectError::from_response(response))),
+        );
+    }
+    Box::new(future::ok(response.headers.get("ETag").cloned()))
+}
+
+/// Retry whatever the store says might work next time, backing off in between
+fn with_retry<T, F>(mut f: F) -> Result<T, StupidS3Error>
+where
+    F: FnMut() -> Result<T, StupidS3Error>,
+{
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(ref e) if e.is_retryable() && attempt < MAX_RETRIES => {
+                attempt += 1;
+                let wait = BASE_BACKOFF * 2u32.pow(attempt - 1);
+                log::warn!(
+                    "{}, retrying in {:?} (attempt {}/{})",
+                    e,
+                    wait,
+                    attempt,
+                    MAX_RETRIES
+                );
+                thread::sleep(wait);
+            }
+            r => return r,
+        }
+    }
+}
+
+impl ObjectStore for StupidS3 {
+    fn get_bytes(&self, key: &str) -> Result<Versioned<Vec<u8>>, StupidS3Error> {
+        with_retry(|| {
+            let resp = self
+                .client
+                .get_object(GetObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.into(),
+                    ..Default::default()
+                })
+                .sync()
+                .map_err(|e| match e {
+                    RusotoError::Service(GetObjectError::NoSuchKey(_)) => {
+                        StupidS3Error::NoSuchKey {
+                            bucket: self.bucket.clone(),
+                            key: key.into(),
+                        }
+                    }
+                    _ => self.classify(key, e),
+                })?;
+            let bytes = resp.body.ok_or(StupidS3Error::ContentMissing)?;
+            let mut value = Vec::new();
+            // the body is streamed, so this fails when the connection does
+            bytes
+                .into_blocking_read()
+                .read_to_end(&mut value)
+                .map_err(|e| StupidS3Error::Unavailable {
+                    bucket: self.bucket.clone(),
+                    key: key.into(),
+                    error: e.into(),
+                })?;
+            Ok(Versioned {
+                value,
+                etag: resp.e_tag.ok_or(StupidS3Error::ContentMissing)?,
+            })
+        })
+    }
+
+    /// rusoto can't send `If-Match` or `If-None-Match` with a PUT, so we sign
+    /// the request ourselves and S3 checks the ETag as part of the write.
+    fn put_bytes(&self, key: &str, body: Vec<u8>, expect: Expect) -> Result<String, StupidS3Error> {
+        let etag = with_retry(|| {
+            let path = format!("/{}/{}", self.bucket, key);
+            let mut request = SignedRequest::new("PUT", "s3", &self.region, &path);
+            match expect {
+                Expect::Anything => {}
+                Expect::Missing => request.add_header("If-None-Match", "*"),
+                Expect::ETag(etag) => request.add_header("If-Match", etag),
+            }
+            request.set_payload(Some(body.clone()));
+            self.http
+                .sign_and_dispatch(request, put_response)
+                .sync()
+                .map_err(|e| self.classify(key, e))
+        })?;
+        etag.ok_or(StupidS3Error::ContentMissing)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>, StupidS3Error> {
+        let mut objects = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let resp = with_retry(|| {
+                self.client
+                    .list_objects_v2(ListObjectsV2Request {
+                        bucket: self.bucket.clone(),
+                        prefix: Some(prefix.into()),
+                        continuation_token: continuation_token.clone(),
+                        ..Default::default()
+                    })
+                    .sync()
+                    .map_err(|e| self.classify(prefix, e))
+            })?;
+            objects.extend(
+                resp.contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|o| {
+                        Some(ObjectInfo {
+                            key: o.key?,
+                            etag: o.e_tag,
+                        })
+                    }),
+            );
+            match resp.next_continuation_token {
+                Some(token) if resp.is_truncated == Some(true) => continuation_token = Some(token),
+                _ => break,
+            }
+        }
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StupidS3Error> {
+        with_retry(|| {
+            self.client
+                .delete_object(DeleteObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: key.into(),
+                    ..Default::default()
+                })
+                .sync()
+                .map_err(|e| self.classify(key, e))
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StupidS3;
+    use crate::{Expect, ObjectStore, StupidS3Error};
+    use futures::future::{self, FutureResult};
+    use http::{HeaderMap, StatusCode};
+    use rusoto_core::{
+        credential::StaticProvider,
+        request::{HttpDispatchError, HttpResponse},
+        signature::SignedRequest,
+        ByteStream, Client, DispatchSignedRequest, Region,
+    };
+    use std::{sync::Mutex, time::Duration};
+
+    /// A bucket holding one object, which honours conditional writes
+    #[derive(Default)]
+    struct Bucket {
+        writes: Mutex<u32>,
+    }
+
+    impl DispatchSignedRequest for Bucket {
+        type Future = FutureResult<HttpResponse, HttpDispatchError>;
+
+        fn dispatch(&self, request: SignedRequest, _: Option<Duration>) -> Self::Future {
+            let header = |name: &str| {
+                request
+                    .headers()
+                    .get(name)
+                    .map(|v| String::from_utf8(v[0].clone()).unwrap())
+            };
+            let mut writes = self.writes.lock().unwrap();
+            let current = Some(format!("\"{}\"", writes)).filter(|_| *writes > 0);
+            let ok = match (header("if-match"), header("if-none-match")) {
+                (Some(etag), _) => current == Some(etag),
+                (None, Some(_)) => current.is_none(),
+                (None, None) => true,
+            };
+            let mut headers = HeaderMap::<String>::default();
+            let status = if ok {
+                *writes += 1;
+                headers.insert("ETag", format!("\"{}\"", writes));
+                StatusCode::OK
+            } else {
+                StatusCode::PRECONDITION_FAILED
+            };
+            future::ok(HttpResponse {
+                status,
+                body: ByteStream::from(Vec::new()),
+                headers,
+            })
+        }
+    }
+
+    #[test]
+    fn conditional_writes_are_checked_by_s3() {
+        let http = Client::new_with(
+            StaticProvider::new_minimal("key".into(), "secret".into()),
+            Bucket::default(),
+        );
+        let s3 = StupidS3::with_client(http, Region::UsWest2, "bucket");
+        let put = |expect| s3.put_bytes("state.json", b"{}".to_vec(), expect);
+
+        let first = put(Expect::Missing).unwrap();
+        assert!(matches!(
+            put(Expect::Missing),
+            Err(StupidS3Error::PreconditionFailed { .. })
+        ));
+        let second = put(Expect::ETag(&first)).unwrap();
+        assert_ne!(first, second);
+        assert!(matches!(
+            put(Expect::ETag(&first)),
+            Err(StupidS3Error::PreconditionFailed { .. })
+        ));
+        put(Expect::Anything).unwrap();
+    }
+}